@@ -1,13 +1,22 @@
 use proc_macro::TokenStream;
 use proc_macro2::TokenStream as TokenStream2;
-use quote::quote;
+use quote::{quote, ToTokens};
+use serde_json::json;
 use syn::{parse_macro_input, visit::Visit};
 
 struct Handler {
     name: String,
+    params: Vec<(String, String)>,
+    result: String,
     src: TokenStream2,
 }
 
+/// Render a type the way it was written, e.g. `Vec<String>` rather than the
+/// token stream's `Vec < String >`.
+fn type_name(ty: &impl ToTokens) -> String {
+    ty.to_token_stream().to_string().replace(' ', "")
+}
+
 struct HandleVisitor {
     impl_trait: syn::Path,
     impl_type: syn::Type,
@@ -25,6 +34,26 @@ impl<'ast> Visit<'ast> for HandleVisitor {
         });
         let indexes = (0..typed_args.clone().count()).map(syn::Index::from);
 
+        let params = sig
+            .inputs
+            .iter()
+            .filter_map(|input| match input {
+                syn::FnArg::Typed(x) => Some(x),
+                _ => None,
+            })
+            .map(|x| {
+                let name = match &*x.pat {
+                    syn::Pat::Ident(pat) => pat.ident.to_string(),
+                    _ => "_".to_string(),
+                };
+                (name, type_name(&x.ty))
+            })
+            .collect();
+        let result = match &sig.output {
+            syn::ReturnType::Default => "()".to_string(),
+            syn::ReturnType::Type(_, ty) => type_name(ty),
+        };
+
         let args = quote! { (#(#typed_args,)*) };
         let args_splat = quote! { #(args.#indexes),* };
 
@@ -33,6 +62,8 @@ impl<'ast> Visit<'ast> for HandleVisitor {
 
         self.handlers.push(Handler {
             name: name.to_string(),
+            params,
+            result,
             src: quote! {
                 let args: #args = serde_json::from_slice(&json).unwrap();
                 let result = <#impl_type as #impl_trait>::#name(#args_splat);
@@ -60,6 +91,22 @@ pub fn export_debug_handler(_attr: TokenStream, item: TokenStream) -> TokenStrea
     let handle_names = visitor.handlers.iter().map(|h| &h.name);
     let handle_srcs = visitor.handlers.iter().map(|h| &h.src);
 
+    // the catalog is fixed at compile time, so it's rendered here and
+    // embedded as a string literal
+    let catalog = json!(visitor
+        .handlers
+        .iter()
+        .map(|h| json!({
+            "name": h.name,
+            "params": h.params.iter().map(|(name, ty)| json!({
+                "name": name,
+                "type": ty,
+            })).collect::<Vec<_>>(),
+            "result": h.result,
+        }))
+        .collect::<Vec<_>>())
+    .to_string();
+
     let debugger_wit = include_str!("../../debugger/debugger.wit");
 
     quote! {
@@ -78,6 +125,10 @@ pub fn export_debug_handler(_attr: TokenStream, item: TokenStream) -> TokenStrea
                     _ => panic!("unknown handler")
                 }
             }
+
+            fn list_handlers() -> String {
+                #catalog.to_string()
+            }
         }
     }
     .into()
@@ -0,0 +1,294 @@
+//! Help for stepping through guest code with a native debugger such as LLDB,
+//! using the DWARF wasmtime generates for the JIT-compiled module.
+
+use anyhow::Result;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::registry::Registry;
+
+/// Pauses guest calls until a debugger attaches to the process or enter is
+/// pressed.
+#[derive(Debug)]
+pub struct Waiter {
+    keys: Mutex<Receiver<()>>,
+}
+
+impl Waiter {
+    /// How often to check whether a debugger has attached while paused.
+    const POLL: Duration = Duration::from_millis(100);
+
+    /// Start reading stdin for enter key presses.
+    pub fn spawn() -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for _ in io::stdin().lock().lines() {
+                if tx.send(()).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            keys: Mutex::new(rx),
+        }
+    }
+
+    /// Block before calling `function`, unless a debugger is already attached.
+    pub fn wait(&self, function: &str) {
+        if debugger_attached() {
+            return;
+        }
+        {
+            // enter pressed while nothing was paused doesn't count
+            let keys = self.keys.lock().unwrap();
+            while keys.try_recv().is_ok() {}
+        }
+        eprintln!(
+            "paused before {}: attach a debugger to pid {} or press enter",
+            function,
+            process::id()
+        );
+
+        loop {
+            // the lock is only held for a poll, so calls paused at the same
+            // time each get a turn and each resume on a press of their own
+            let pressed = self.keys.lock().unwrap().recv_timeout(Self::POLL);
+            match pressed {
+                Ok(()) => return,
+                Err(RecvTimeoutError::Timeout) => {}
+                // stdin is closed, so only a debugger can resume the call
+                Err(RecvTimeoutError::Disconnected) => thread::sleep(Self::POLL),
+            }
+            if debugger_attached() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn debugger_attached() -> bool {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("TracerPid:"))
+                .map(|pid| pid.trim() != "0")
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn debugger_attached() -> bool {
+    false
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A thread running every guest call, so breakpoints set on one thread of
+/// the JIT code are hit no matter which connection a request came in on.
+pub struct GuestThread {
+    jobs: Mutex<Sender<Job>>,
+}
+
+impl GuestThread {
+    pub fn spawn() -> Result<Self> {
+        let (tx, rx) = mpsc::channel::<Job>();
+        thread::Builder::new().name("guest".into()).spawn(move || {
+            for job in rx {
+                job();
+            }
+        })?;
+        Ok(Self {
+            jobs: Mutex::new(tx),
+        })
+    }
+
+    /// Run `f` on the guest thread and wait for its result.
+    pub fn run<R: Send + 'static>(&self, f: impl FnOnce() -> R + Send + 'static) -> R {
+        let (tx, rx) = mpsc::channel();
+        self.jobs
+            .lock()
+            .unwrap()
+            .send(Box::new(move || {
+                let _ = tx.send(f());
+            }))
+            .expect("the guest thread has exited");
+        rx.recv().expect("the guest thread panicked")
+    }
+}
+
+/// The LLDB commands attaching to this process and breaking in every
+/// function the guests export.
+pub fn lldb_commands(functions: &[String]) -> Vec<String> {
+    let mut commands = vec![
+        // wasmtime registers its JIT code through the GDB JIT interface
+        "settings set plugin.jit-loader.gdb.enable on".to_string(),
+        format!("process attach --pid {}", process::id()),
+    ];
+    commands.extend(
+        functions
+            .iter()
+            .map(|function| format!("breakpoint set --name {}", function)),
+    );
+    commands.push("continue".to_string());
+    commands
+}
+
+/// The commands that stop on a line of each of `files`, or on a line of any
+/// file when the module has no debug info naming them.
+pub fn line_breakpoints(files: &[String]) -> Vec<String> {
+    if files.is_empty() {
+        return vec!["breakpoint set --file <FILE> --line <LINE>".to_string()];
+    }
+    files
+        .iter()
+        .map(|file| format!("breakpoint set --file {} --line <LINE>", file))
+        .collect()
+}
+
+/// The source files of a module's own crate, as its DWARF names them,
+/// leaving out the standard library and dependencies.
+pub fn source_files(wasm_path: &Path) -> Result<Vec<String>> {
+    let wasm = fs::read(wasm_path)?;
+    let mut sections = HashMap::new();
+    for payload in wasmparser::Parser::new(0).parse_all(&wasm) {
+        if let wasmparser::Payload::CustomSection { name, data, .. } = payload? {
+            sections.insert(name, data);
+        }
+    }
+    let dwarf = gimli::Dwarf::load(|id| -> Result<_> {
+        let data = sections.get(id.name()).copied().unwrap_or(&[]);
+        Ok(gimli::EndianSlice::new(data, gimli::LittleEndian))
+    })?;
+
+    let mut files = BTreeSet::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let (program, comp_dir) = match (&unit.line_program, &unit.comp_dir) {
+            (Some(program), Some(dir)) => (program, PathBuf::from(&*dir.to_string_lossy())),
+            _ => continue,
+        };
+        // the standard library and its dependencies are remapped to
+        // /rustc/<commit> and /rust/deps, and other dependencies are built
+        // where cargo downloaded them
+        let dir = comp_dir.to_string_lossy();
+        let skipped = [
+            "/rustc/",
+            "/rust/deps/",
+            "/.cargo/registry/",
+            "/.cargo/git/",
+        ];
+        if skipped
+            .iter()
+            .any(|prefix| dir.starts_with(prefix) || dir.contains(prefix))
+        {
+            continue;
+        }
+        let header = program.header();
+        for file in header.file_names() {
+            let mut path = comp_dir.clone();
+            if let Some(dir) = file.directory(header) {
+                path.push(&*dwarf.attr_string(&unit, dir)?.to_string_lossy());
+            }
+            path.push(
+                &*dwarf
+                    .attr_string(&unit, file.path_name())?
+                    .to_string_lossy(),
+            );
+            // files of other crates turn up in a unit through inlining
+            if let Ok(relative) = path.strip_prefix(&comp_dir) {
+                files.insert(relative.display().to_string());
+            }
+        }
+    }
+    Ok(files.into_iter().collect())
+}
+
+/// Print how to attach LLDB to the debugger and break in the guests.
+pub fn print_instructions(registry: &Registry, single_thread: bool) -> Result<()> {
+    let mut functions = Vec::new();
+    let mut files = BTreeSet::new();
+    for module in registry.modules() {
+        let wasm_path = module.pool.factory().wasm_path().to_path_buf();
+        match source_files(&wasm_path) {
+            Ok(found) => files.extend(found),
+            Err(err) => eprintln!(
+                "warning: can't read the debug info of {}: {}",
+                wasm_path.display(),
+                err
+            ),
+        }
+        for handler in module
+            .pool
+            .factory()
+            .make_handler()?
+            .list_handlers(module.signatures.as_deref())?
+        {
+            functions.push(handler.name);
+        }
+    }
+
+    println!(
+        "debugger pid {}, to step through guest code run lldb and:",
+        process::id()
+    );
+    for command in lldb_commands(&functions) {
+        println!("  (lldb) {}", command);
+    }
+    println!("to stop on a particular line, before continuing:");
+    for command in line_breakpoints(&files.into_iter().collect::<Vec<_>>()) {
+        println!("  (lldb) {}", command);
+    }
+    if single_thread {
+        println!("guest calls run on the thread named \"guest\"");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lldb_commands() {
+        let commands = lldb_commands(&["power_of".to_string()]);
+        assert_eq!(
+            commands[1],
+            format!("process attach --pid {}", process::id())
+        );
+        assert_eq!(
+            commands[2..],
+            ["breakpoint set --name power_of", "continue"]
+        );
+    }
+
+    #[test]
+    fn test_line_breakpoints() {
+        assert_eq!(
+            line_breakpoints(&["src/lib.rs".to_string()]),
+            ["breakpoint set --file src/lib.rs --line <LINE>"]
+        );
+        assert_eq!(
+            line_breakpoints(&[]),
+            ["breakpoint set --file <FILE> --line <LINE>"]
+        );
+    }
+
+    #[test]
+    fn test_guest_thread() {
+        let thread = GuestThread::spawn().unwrap();
+        let name = thread.run(|| thread::current().name().map(String::from));
+        assert_eq!(name.as_deref(), Some("guest"));
+        assert_eq!(thread.run(|| 1 + 1), 2);
+    }
+}
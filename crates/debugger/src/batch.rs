@@ -0,0 +1,374 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::panic;
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::coerce;
+use crate::handle;
+use crate::registry::Module;
+use crate::shape;
+use crate::signature::Signature;
+
+pub type Row = Vec<Value>;
+
+/// A JSON request or response body.
+#[derive(Serialize, Deserialize)]
+pub struct Payload {
+    pub data: Vec<Row>,
+}
+
+/// How errors raised while processing a row are reported.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorMode {
+    /// Fail the whole request on the first bad row.
+    Fail,
+    /// Keep going and return an error document listing every failed row.
+    Rows,
+}
+
+impl Default for ErrorMode {
+    fn default() -> Self {
+        ErrorMode::Fail
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Options {
+    pub errors: ErrorMode,
+    /// Wrap every result in an envelope holding the guest's stdout and
+    /// stderr for that row. Also enabled by the `x-debug-output` header.
+    pub debug: bool,
+    /// Seed the guest's random numbers with this rather than the configured
+    /// seed, when running deterministically. Also set by the `x-debug-seed`
+    /// header.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RowError {
+    pub row: Value,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub backtrace: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub panic: Option<String>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub output: Option<handle::CapturedOutput>,
+}
+
+impl RowError {
+    pub fn new(row: Value, err: &anyhow::Error) -> Self {
+        match err.downcast_ref::<handle::GuestError>() {
+            Some(guest) => Self {
+                row,
+                message: guest.message.clone(),
+                backtrace: guest.backtrace.clone(),
+                panic: guest.panic.clone(),
+                output: None,
+            },
+            None => Self {
+                row,
+                message: err.to_string(),
+                backtrace: vec![],
+                panic: None,
+                output: None,
+            },
+        }
+    }
+}
+
+/// The first row to fail with [`ErrorMode::Fail`], which aborts the batch.
+#[derive(Debug)]
+pub struct RowFailed {
+    pub row: Value,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for RowFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "row {}: {}", self.row, self.error)
+    }
+}
+
+impl std::error::Error for RowFailed {}
+
+/// The result rows of a batch, of the form `[id, values...]`, and the rows
+/// that failed with [`ErrorMode::Rows`].
+#[derive(Debug, Default)]
+pub struct Outcome {
+    pub data: Vec<Row>,
+    pub errors: Vec<RowError>,
+}
+
+/// Bounds how many guest instances compute rows at once, across every
+/// request and module, since each of them keeps a thread busy.
+#[derive(Debug)]
+pub struct Slots {
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Slots {
+    pub fn new(count: usize) -> Self {
+        Self {
+            free: Mutex::new(count),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Wait for a free slot, which is given back when the guard is dropped.
+    fn acquire(&self) -> Slot<'_> {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.freed.wait(free).unwrap();
+        }
+        *free -= 1;
+        Slot(self)
+    }
+}
+
+struct Slot<'a>(&'a Slots);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Call `function` on every row of a batch. Rows are of the form
+/// `[id, args...]`.
+///
+/// When the module computes batches in parallel, the batch is split into
+/// that many runs of consecutive rows, each computed on its own thread and
+/// instance, and the results are put back in the order of the rows.
+pub fn run(
+    module: &Module,
+    connection: &str,
+    function: &str,
+    signature: Option<&Signature>,
+    rows: Vec<Row>,
+    options: &Options,
+) -> Result<Outcome> {
+    module.stats.record_request(function);
+    if module.parallel <= 1 || rows.len() <= 1 {
+        return run_rows(module, connection, function, signature, &rows, options);
+    }
+
+    in_parallel(&rows, module.parallel, |chunk, _| {
+        run_rows(module, connection, function, signature, chunk, options)
+    })
+}
+
+/// Split `rows` into `parts` runs of consecutive rows, compute each of them
+/// with `f` on a thread of its own, and put the results back in the order of
+/// the rows. `f` is given a run and the index of its first row.
+fn in_parallel<F>(rows: &[Row], parts: usize, f: F) -> Result<Outcome>
+where
+    F: Fn(&[Row], usize) -> Result<Outcome> + Sync,
+{
+    let chunk_size = rows.len().div_ceil(parts).max(1);
+    let f = &f;
+    let outcomes: Vec<_> = thread::scope(|scope| {
+        let workers: Vec<_> = rows
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(i, chunk)| scope.spawn(move || f(chunk, i * chunk_size)))
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| {
+                worker
+                    .join()
+                    .unwrap_or_else(|err| panic::resume_unwind(err))
+            })
+            .collect()
+    });
+
+    // the first row to fail in a batch that stops on errors is the first one
+    // in the batch, not the first one some thread got to
+    let mut outcome = Outcome::default();
+    for chunk in outcomes {
+        let chunk = chunk?;
+        outcome.data.extend(chunk.data);
+        outcome.errors.extend(chunk.errors);
+    }
+    Ok(outcome)
+}
+
+/// Call `function` on consecutive rows of a batch.
+fn run_rows(
+    module: &Module,
+    connection: &str,
+    function: &str,
+    signature: Option<&Signature>,
+    rows: &[Row],
+    options: &Options,
+) -> Result<Outcome> {
+    let _slot = module.slots.acquire();
+    let mut handler = module.pool.checkout(connection)?;
+    let kind = module.kind(function);
+
+    let mut outcome = Outcome::default();
+    for row in rows {
+        if row.is_empty() {
+            return Err(anyhow!("Empty row"));
+        }
+
+        let row_id = row[0].clone();
+        let row_input = row[1..].to_vec();
+
+        handler.restart_wasi(options.seed, &row_id);
+        handler.clear_captured();
+        let started = Instant::now();
+        let res = call_row(&mut handler, function, signature, row_input);
+        module
+            .stats
+            .record(function, started.elapsed(), res.as_ref().err());
+
+        let output = match res {
+            Ok(output) => output,
+            Err(err) if options.errors == ErrorMode::Rows => {
+                let mut row_error = RowError::new(row_id, &err);
+                if options.debug {
+                    row_error.output = Some(handler.captured().clone());
+                }
+                outcome.errors.push(row_error);
+                // a trap can leave the instance in an inconsistent state, so
+                // the remaining rows get another one; the pool recycles the
+                // trapped instance once it's returned
+                if handler.trapped() {
+                    handler = module.pool.checkout(connection)?;
+                }
+                continue;
+            }
+            Err(error) => return Err(RowFailed { row: row_id, error }.into()),
+        };
+
+        let encode_value = |v: &Value| match v {
+            Value::Array(_) | Value::Object(_) => Value::String(serde_json::to_string(v).unwrap()),
+            _ => v.clone(),
+        };
+
+        for columns in shape::shape(output, kind, signature) {
+            let columns = if options.debug {
+                let value = match <[Value; 1]>::try_from(columns) {
+                    Ok([value]) => value,
+                    Err(columns) => Value::Array(columns),
+                };
+                vec![envelope(value, handler.captured())]
+            } else {
+                columns
+            };
+
+            let mut row = vec![row_id.clone()];
+            row.extend(columns.iter().map(encode_value));
+            outcome.data.push(row);
+        }
+    }
+
+    Ok(outcome)
+}
+
+fn call_row(
+    handler: &mut handle::Handler,
+    name: &str,
+    signature: Option<&Signature>,
+    mut args: Vec<Value>,
+) -> Result<Value> {
+    if let Some(sig) = signature {
+        args = coerce::coerce_args(sig, args)?;
+    }
+
+    let output_raw = handler.handle_json(name.into(), serde_json::to_vec(&args)?)?;
+    Ok(serde_json::from_slice(&output_raw)?)
+}
+
+/// Wrap a result with the output the guest produced while computing it.
+fn envelope(value: Value, output: &handle::CapturedOutput) -> Value {
+    json!({
+        "value": value,
+        "stdout": output.stdout,
+        "stderr": output.stderr,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    fn rows(count: i64) -> Vec<Row> {
+        (0..count)
+            .map(|id| vec![json!(id), json!(id * 10)])
+            .collect()
+    }
+
+    /// Doubles every argument, taking longer on the earlier rows so later
+    /// runs finish first.
+    fn double(rows: &[Row], first: usize) -> Result<Outcome> {
+        thread::sleep(Duration::from_millis(20u64.saturating_sub(first as u64)));
+        let mut outcome = Outcome::default();
+        for row in rows {
+            if row[1] == json!(-1) {
+                return Err(RowFailed {
+                    row: row[0].clone(),
+                    error: anyhow!("negative"),
+                }
+                .into());
+            }
+            let value = row[1].as_i64().unwrap() * 2;
+            outcome.data.push(vec![row[0].clone(), json!(value)]);
+        }
+        Ok(outcome)
+    }
+
+    #[test]
+    fn test_in_parallel_keeps_row_order() {
+        let outcome = in_parallel(&rows(10), 4, double).unwrap();
+        let expected: Vec<Row> = (0..10).map(|id| vec![json!(id), json!(id * 20)]).collect();
+        assert_eq!(outcome.data, expected);
+
+        // more parts than rows
+        assert_eq!(in_parallel(&rows(2), 8, double).unwrap().data.len(), 2);
+    }
+
+    #[test]
+    fn test_in_parallel_reports_first_failing_row() {
+        let mut rows = rows(10);
+        rows[2][1] = json!(-1);
+        rows[8][1] = json!(-1);
+        // the run holding row 8 fails first, since earlier runs are slower
+        let err = in_parallel(&rows, 4, double).unwrap_err();
+        assert_eq!(err.downcast_ref::<RowFailed>().unwrap().row, json!(2));
+    }
+
+    #[test]
+    fn test_slots_bound_concurrency() {
+        let slots = Slots::new(2);
+        let (held, most) = (AtomicUsize::new(0), AtomicUsize::new(0));
+        thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    let _slot = slots.acquire();
+                    let now = held.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(5));
+                    held.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        // how many threads overlap is up to the scheduler, but never more
+        // than there are slots
+        assert!(most.load(Ordering::SeqCst) <= 2);
+        assert_eq!(*slots.free.lock().unwrap(), 2);
+    }
+}
@@ -0,0 +1,78 @@
+use anyhow::{anyhow, Context, Result};
+use serde_json::Value;
+use std::fs;
+use std::time::{Duration, Instant};
+
+use crate::batch::{self, Payload, Row};
+use crate::cli::BenchArgs;
+use crate::registry::Registry;
+
+/// Run the same batch through a function over and over, and report how long
+/// each batch took.
+pub fn run(registry: &Registry, args: &BenchArgs) -> Result<()> {
+    let (module, function) = match args.function.split_once('/') {
+        Some((module, function)) => (registry.get(module), function),
+        None => (registry.only(), args.function.as_str()),
+    };
+    let module = module
+        .ok_or_else(|| anyhow!("unknown module, name the function as <module>/{}", function))?;
+    let signature = module.signature(function)?;
+
+    let rows: Vec<Row> = match &args.payload {
+        Some(path) => {
+            let payload =
+                fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+            serde_json::from_slice::<Payload>(&payload)?.data
+        }
+        None => {
+            let row_args: Vec<Value> =
+                serde_json::from_str(&args.row_args).context("--args must be a JSON array")?;
+            (0..args.rows)
+                .map(|id| {
+                    let mut row = vec![Value::from(id)];
+                    row.extend(row_args.iter().cloned());
+                    row
+                })
+                .collect()
+        }
+    };
+
+    println!(
+        "{}/{}: {} batches of {} rows",
+        module.name,
+        function,
+        args.iterations,
+        rows.len()
+    );
+
+    let mut timings = Vec::with_capacity(args.iterations);
+    for _ in 0..args.iterations {
+        let started = Instant::now();
+        batch::run(
+            &module,
+            "bench",
+            function,
+            signature,
+            rows.clone(),
+            &batch::Options::default(),
+        )?;
+        timings.push(started.elapsed());
+    }
+
+    if timings.is_empty() {
+        return Ok(());
+    }
+    let total: Duration = timings.iter().sum();
+    let mean = total / timings.len() as u32;
+    println!(
+        "  min {:?}  mean {:?}  max {:?} per batch",
+        timings.iter().min().unwrap(),
+        mean,
+        timings.iter().max().unwrap()
+    );
+    println!(
+        "  {:.0} rows/s",
+        (rows.len() * timings.len()) as f64 / total.as_secs_f64()
+    );
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use anyhow::{anyhow, Context, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::fmt;
+use std::time::Instant;
+
+use crate::cli::CallArgs;
+use crate::coerce;
+use crate::handle::{CapturedOutput, FactoryOptions, GuestError, HandleFactory};
+use crate::signature::{handler_name, Signatures};
+
+/// Everything a call produced, printed with `--json`.
+#[derive(Serialize)]
+struct Report {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    backtrace: Vec<String>,
+    #[serde(flatten)]
+    output: CapturedOutput,
+    duration_ms: f64,
+}
+
+/// A failed call whose error is already part of the `--json` report, so it
+/// only sets the exit status rather than being printed again.
+#[derive(Debug)]
+pub struct Reported;
+
+impl fmt::Display for Reported {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the call failed")
+    }
+}
+
+impl std::error::Error for Reported {}
+
+/// Call a function once, without going through the server. The result goes
+/// to stdout and everything else to stderr, unless `--json` asks for a
+/// single document.
+pub fn run(args: &CallArgs) -> Result<()> {
+    let factory = HandleFactory::new(
+        &args.wasm,
+        FactoryOptions {
+            limits: args.limits.limits(),
+            wasi: args.wasi.options()?,
+            ..FactoryOptions::default()
+        },
+    )?;
+
+    let mut call_args = args
+        .call_args
+        .iter()
+        .map(|arg| serde_json::from_str(arg).with_context(|| format!("{} isn't a JSON value", arg)))
+        .collect::<Result<Vec<Value>>>()?;
+
+    if let Some(wit_path) = &args.wit {
+        let signatures = Signatures::from_file(wit_path)?;
+        let sig = signatures
+            .get(&args.function)
+            .ok_or_else(|| anyhow!("function {} is not declared in the WIT file", args.function))?;
+        call_args = coerce::coerce_args(sig, call_args)?;
+    }
+
+    let mut handler = factory.make_handler()?;
+    handler.set_echo(false);
+
+    let started = Instant::now();
+    let res = handler
+        .handle_json(
+            handler_name(&args.function),
+            serde_json::to_vec(&call_args)?,
+        )
+        .and_then(|output| serde_json::from_slice::<Value>(&output).map_err(Into::into));
+    let elapsed = started.elapsed();
+    let output = handler.captured().clone();
+
+    if args.json {
+        let report = match &res {
+            Ok(result) => Report {
+                result: Some(result.clone()),
+                error: None,
+                backtrace: vec![],
+                output,
+                duration_ms: elapsed.as_secs_f64() * 1000.0,
+            },
+            Err(err) => Report {
+                result: None,
+                error: Some(err.to_string()),
+                backtrace: match err.downcast_ref::<GuestError>() {
+                    Some(guest) => guest.backtrace.clone(),
+                    None => vec![],
+                },
+                output,
+                duration_ms: elapsed.as_secs_f64() * 1000.0,
+            },
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        if res.is_err() {
+            return Err(Reported.into());
+        }
+    } else {
+        if !output.stdout.is_empty() {
+            eprintln!("--- guest stdout ---\n{}", output.stdout.trim_end());
+        }
+        if !output.stderr.is_empty() {
+            eprintln!("--- guest stderr ---\n{}", output.stderr.trim_end());
+        }
+        if let Some(guest) = res
+            .as_ref()
+            .err()
+            .and_then(|e| e.downcast_ref::<GuestError>())
+        {
+            eprintln!("--- backtrace ---");
+            for frame in &guest.backtrace {
+                eprintln!("{}", frame);
+            }
+        }
+        eprintln!("took {:?}", elapsed);
+        if let Ok(result) = &res {
+            println!("{}", serde_json::to_string_pretty(result)?);
+        }
+    }
+
+    res.map(|_| ())
+}
@@ -0,0 +1,647 @@
+use anyhow::{anyhow, Context, Result};
+use clap::{Args, Parser, Subcommand};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use tide::log::LevelFilter;
+
+use crate::attach::Waiter;
+use crate::handle::{FactoryOptions, Init, Lifetime, Profiler};
+use crate::limits::Limits;
+use crate::registry::{self, ModuleSpec, Registry};
+use crate::signature::FunctionKind;
+use crate::wasi::{self, Clock, Preopen, Random, WasiOptions};
+
+/// Run SingleStoreDB Wasm functions outside of the database.
+#[derive(Parser, Debug)]
+#[clap(name = "debugger", version)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Serve modules over HTTP as SingleStoreDB external functions
+    Serve(ServeArgs),
+    /// Run recorded requests again and report rows whose results changed
+    Replay(ReplayArgs),
+    /// Measure how long a function takes to process batches of rows
+    Bench(BenchArgs),
+    /// Call a function once and print its result
+    Call(CallArgs),
+    /// Call a module's functions interactively
+    Repl(ReplArgs),
+    /// Check that calls produce the results recorded in a golden file
+    Test(TestArgs),
+    /// Print the SQL that creates a module's functions in SingleStoreDB
+    Sql(SqlArgs),
+    /// Compile modules ahead of time, so the debugger starts without
+    /// compiling them
+    Compile(CompileArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on; use 0.0.0.0 when SingleStoreDB runs in a
+    /// container or on another host
+    #[clap(long, default_value = "127.0.0.1")]
+    pub bind: IpAddr,
+
+    /// Port to listen on
+    #[clap(long, short, default_value = "3000")]
+    pub port: u16,
+
+    /// One of off, error, warn, info, debug or trace
+    #[clap(long, default_value = "info")]
+    pub log_level: LevelFilter,
+
+    /// Append every request and its results to this JSONL file
+    #[clap(long, env = "DEBUGGER_RECORD", value_name = "PATH")]
+    pub record: Option<PathBuf>,
+
+    /// Print the SQL declaring every function as an external function on
+    /// startup
+    #[clap(long)]
+    pub print_sql: bool,
+
+    /// The host SingleStoreDB reaches the debugger at, for the printed SQL;
+    /// defaults to the --bind address
+    #[clap(long, value_name = "HOST")]
+    pub sql_host: Option<String>,
+
+    /// Pause before every guest call until a native debugger such as LLDB
+    /// attaches, or enter is pressed
+    #[clap(long)]
+    pub wait_for_debugger: bool,
+
+    /// Run every guest call on one dedicated thread, so breakpoints in the
+    /// guest's code are hit reliably
+    #[clap(long)]
+    pub single_thread: bool,
+
+    #[clap(flatten)]
+    pub modules: ModuleArgs,
+}
+
+impl ServeArgs {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    /// Load the modules, pausing their calls for a native debugger if asked
+    /// to.
+    pub fn load(&self) -> Result<Registry> {
+        let mut config = self.modules.config()?;
+        if self.wait_for_debugger {
+            config.factory.wait = Some(Arc::new(Waiter::spawn()));
+        }
+        if self.single_thread {
+            // every call has to run on the guest thread
+            config.parallel = 1;
+        }
+        Registry::load(self.modules.specs()?, &config)
+    }
+
+    /// The `host:port` external functions are declared with.
+    pub fn sql_host(&self) -> String {
+        match &self.sql_host {
+            Some(host) => format!("{}:{}", host, self.port),
+            None => self.addr().to_string(),
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ReplayArgs {
+    /// A file recorded with `serve --record`
+    pub recording: PathBuf,
+
+    #[clap(flatten)]
+    pub modules: ModuleArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// The function to call, as `[module/]function`
+    #[clap(long, short)]
+    pub function: String,
+
+    /// The arguments of every row, as a JSON array
+    #[clap(
+        long = "args",
+        default_value = "[]",
+        value_name = "JSON",
+        conflicts_with = "payload"
+    )]
+    pub row_args: String,
+
+    /// A request body to use as the batch instead, as `{"data": [[id, args...], ...]}`
+    #[clap(long, value_name = "PATH")]
+    pub payload: Option<PathBuf>,
+
+    /// Rows in each batch built from --args
+    #[clap(long, default_value = "1000")]
+    pub rows: usize,
+
+    /// Number of batches to run
+    #[clap(long, default_value = "10")]
+    pub iterations: usize,
+
+    #[clap(flatten)]
+    pub modules: ModuleArgs,
+}
+
+#[derive(Args, Debug)]
+#[clap(allow_negative_numbers = true)]
+pub struct CallArgs {
+    /// The module to load
+    pub wasm: PathBuf,
+
+    /// The function to call
+    pub function: String,
+
+    /// The function's arguments, each as a JSON value, e.g. `2` or `'"text"'`
+    #[clap(value_name = "ARG")]
+    pub call_args: Vec<String>,
+
+    /// The module's WIT file, to check and convert the arguments
+    #[clap(long, value_name = "PATH")]
+    pub wit: Option<PathBuf>,
+
+    /// Print the result, the guest's output and the time taken as one JSON
+    /// document
+    #[clap(long)]
+    pub json: bool,
+
+    #[clap(flatten)]
+    pub limits: LimitArgs,
+
+    #[clap(flatten)]
+    pub wasi: WasiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct ReplArgs {
+    /// The module to load
+    pub wasm: PathBuf,
+
+    /// The module's WIT file, to check and convert arguments
+    #[clap(long, value_name = "PATH")]
+    pub wit: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub limits: LimitArgs,
+
+    #[clap(flatten)]
+    pub wasi: WasiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct TestArgs {
+    /// The module to test
+    pub wasm: PathBuf,
+
+    /// The cases to run, as a `.toml` file of `[[case]]` tables or a
+    /// `.jsonl` file with a case on each line
+    pub cases: PathBuf,
+
+    /// The module's WIT file, to check and convert arguments
+    #[clap(long, value_name = "PATH")]
+    pub wit: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub limits: LimitArgs,
+
+    #[clap(flatten)]
+    pub wasi: WasiArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct SqlArgs {
+    /// The module, as SingleStoreDB should load it
+    pub wasm: PathBuf,
+
+    /// The module's WIT file
+    pub wit: PathBuf,
+
+    /// Inline both files as base64 rather than loading them with LOCAL INFILE
+    #[clap(long)]
+    pub base64: bool,
+
+    /// Use CREATE OR REPLACE, to redeploy functions that already exist
+    #[clap(long)]
+    pub or_replace: bool,
+}
+
+/// Modules are compiled for the limits and profiler they'll run with, since
+/// fuel and timeouts change the compiled code.
+#[derive(Args, Debug)]
+pub struct CompileArgs {
+    /// The modules to compile, each written next to it as a `.cwasm` file
+    #[clap(required = true, value_name = "WASM")]
+    pub modules: Vec<PathBuf>,
+
+    /// Compile for an external profiler: none, jitdump (for perf) or vtune;
+    /// perfmap isn't available in wasmtime 0.35
+    #[clap(long, env = "DEBUGGER_PROFILER", default_value = "none")]
+    pub profiler: Profiler,
+
+    #[clap(flatten)]
+    pub limits: LimitArgs,
+}
+
+/// The modules to load and how to run them, shared by every subcommand.
+#[derive(Args, Debug)]
+pub struct ModuleArgs {
+    /// `[name=]path/to/foo.wasm`, optionally followed by `path/to/foo.wit`,
+    /// or a directory of modules
+    #[clap(required = true, value_name = "MODULE")]
+    pub modules: Vec<String>,
+
+    /// The WIT file of a module, as `[name=]path/to/foo.wit`
+    #[clap(long = "wit", value_name = "[NAME=]PATH")]
+    pub wits: Vec<String>,
+
+    /// How long guest instances live: request, connection or forever
+    #[clap(long, env = "DEBUGGER_INSTANCES", default_value = "request")]
+    pub instances: Lifetime,
+
+    /// Serve a function as a UDF or a TVF whatever its WIT signature says
+    #[clap(
+        long = "kind",
+        env = "DEBUGGER_FUNCTION_KINDS",
+        use_value_delimiter = true,
+        value_name = "[MODULE/]FUNCTION=udf|tvf"
+    )]
+    pub kinds: Vec<String>,
+
+    /// Compile guests for an external profiler: none, jitdump (for perf) or
+    /// vtune; perfmap isn't available in wasmtime 0.35
+    #[clap(long, env = "DEBUGGER_PROFILER", default_value = "none")]
+    pub profiler: Profiler,
+
+    /// Start every instance from a snapshot taken after calling an export,
+    /// as `[MODULE/]EXPORT`, or a function with a JSON array of arguments, as
+    /// `[MODULE/]FUNCTION=ARGS`
+    #[clap(long = "init", value_name = "[MODULE/]EXPORT|FUNCTION=ARGS")]
+    pub inits: Vec<String>,
+
+    /// Override an option for one module, as `MODULE/OPTION=VALUE`, where
+    /// OPTION is fuel, timeout-ms, max-memory, max-table-elements, profiler,
+    /// env, arg, dir, dir-rw, clock or random
+    #[clap(long = "module-option", value_name = "MODULE/OPTION=VALUE")]
+    pub overrides: Vec<String>,
+
+    /// Load the `.cwasm` file `debugger compile` wrote next to each module
+    /// rather than compiling it. The file is native code that isn't checked,
+    /// so only use files you compiled yourself
+    #[clap(long, env = "DEBUGGER_PRECOMPILED")]
+    pub precompiled: bool,
+
+    /// Split each batch into this many parts, computed in parallel on
+    /// instances of their own; ignored with `--instances connection`
+    #[clap(long, env = "DEBUGGER_PARALLEL", default_value = "1")]
+    pub parallel: usize,
+
+    /// The most instances computing rows at once, across every request;
+    /// defaults to the number of CPUs
+    #[clap(long, env = "DEBUGGER_MAX_CONCURRENCY", value_name = "COUNT")]
+    pub max_concurrency: Option<usize>,
+
+    #[clap(flatten)]
+    pub limits: LimitArgs,
+
+    #[clap(flatten)]
+    pub wasi: WasiArgs,
+}
+
+impl ModuleArgs {
+    pub fn load(&self) -> Result<Registry> {
+        Registry::load(self.specs()?, &self.config()?)
+    }
+
+    pub fn specs(&self) -> Result<Vec<ModuleSpec>> {
+        let mut specs = ModuleSpec::parse_args(&self.modules)?;
+        for wit in &self.wits {
+            ModuleSpec::attach_wit(&mut specs, wit)?;
+        }
+        Ok(specs)
+    }
+
+    pub fn config(&self) -> Result<registry::Config> {
+        if self.parallel == 0 || self.max_concurrency == Some(0) {
+            return Err(anyhow!(
+                "--parallel and --max-concurrency need to be at least 1"
+            ));
+        }
+        if self.wasi.deterministic && self.instances != Lifetime::Request {
+            // an instance serving several requests carries its memory from
+            // one to the next, which restarting the clocks doesn't undo
+            return Err(anyhow!(
+                "--deterministic needs --instances request, \
+                 since reused instances remember earlier requests"
+            ));
+        }
+        let max_concurrency = match self.max_concurrency {
+            Some(max) => max,
+            None => thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+        };
+        Ok(registry::Config {
+            lifetime: self.instances,
+            factory: FactoryOptions {
+                limits: self.limits.limits(),
+                wasi: self.wasi.options()?,
+                wait: None,
+                profiler: self.profiler,
+                // set for each module by the registry
+                init: vec![],
+                precompiled: self.precompiled,
+            },
+            kinds: self
+                .kinds
+                .iter()
+                .map(|kind| parse_kind(kind))
+                .collect::<Result<_>>()?,
+            inits: self
+                .inits
+                .iter()
+                .map(|init| parse_init(init))
+                .collect::<Result<_>>()?,
+            overrides: self
+                .overrides
+                .iter()
+                .map(|entry| parse_override(entry))
+                .collect::<Result<_>>()?,
+            // a connection's state lives in a single instance, which splitting
+            // its batches would spread over several
+            parallel: match self.instances {
+                Lifetime::Connection => 1,
+                _ => self.parallel,
+            },
+            max_concurrency,
+        })
+    }
+}
+
+/// Per-row resource limits.
+#[derive(Args, Debug)]
+pub struct LimitArgs {
+    /// Fuel available to each row, roughly one unit per wasm instruction
+    #[clap(long, env = "DEBUGGER_FUEL")]
+    pub fuel: Option<u64>,
+
+    /// Wall-clock time allowed for each row, in milliseconds
+    #[clap(long, env = "DEBUGGER_TIMEOUT_MS", value_name = "MS")]
+    pub timeout_ms: Option<u64>,
+
+    /// Maximum size of a guest's linear memory, in bytes
+    #[clap(long, env = "DEBUGGER_MAX_MEMORY", value_name = "BYTES")]
+    pub max_memory: Option<usize>,
+
+    /// Maximum number of elements in any of a guest's tables
+    #[clap(long, env = "DEBUGGER_MAX_TABLE_ELEMENTS", value_name = "COUNT")]
+    pub max_table_elements: Option<u32>,
+}
+
+impl LimitArgs {
+    pub fn limits(&self) -> Limits {
+        Limits {
+            fuel: self.fuel,
+            timeout: self.timeout_ms.map(Duration::from_millis),
+            max_memory: self.max_memory,
+            max_table_elements: self.max_table_elements,
+        }
+    }
+}
+
+/// The guest's WASI environment.
+#[derive(Args, Debug)]
+pub struct WasiArgs {
+    /// Set an environment variable in the guest
+    #[clap(long = "env", value_name = "NAME=VALUE")]
+    pub env: Vec<String>,
+
+    /// Pass an argument to the guest, after its module's file name
+    #[clap(long = "arg", value_name = "ARG", allow_hyphen_values = true)]
+    pub args: Vec<String>,
+
+    /// Let the guest read files in a host directory, which it opens as GUEST
+    /// or by the host path
+    #[clap(long = "dir", value_name = "HOST[::GUEST]")]
+    pub dirs: Vec<String>,
+
+    /// Let the guest read and change files in a host directory
+    #[clap(long = "dir-rw", value_name = "HOST[::GUEST]")]
+    pub writable_dirs: Vec<String>,
+
+    /// The guest's clocks: real (the default), fixed (the unix epoch),
+    /// fixed:SECONDS, stepped (a millisecond per read) or stepped:MILLIS
+    #[clap(long, env = "DEBUGGER_CLOCK")]
+    pub clock: Option<Clock>,
+
+    /// The guest's random numbers: real (the default), or seed:N for the same
+    /// numbers in every instance
+    #[clap(long, env = "DEBUGGER_RANDOM")]
+    pub random: Option<Random>,
+
+    /// Restart the guest's clocks and random numbers before every row, from
+    /// the `x-debug-seed` header when a request has one. Unless told
+    /// otherwise, clocks step a millisecond per read and the seed is 0; real
+    /// clocks and random numbers can't be restarted
+    #[clap(long, env = "DEBUGGER_DETERMINISTIC")]
+    pub deterministic: bool,
+}
+
+impl WasiArgs {
+    pub fn options(&self) -> Result<WasiOptions> {
+        let dirs = self.dirs.iter().map(|dir| Preopen::parse(dir, false));
+        let writable_dirs = self
+            .writable_dirs
+            .iter()
+            .map(|dir| Preopen::parse(dir, true));
+        Ok(WasiOptions {
+            env: self
+                .env
+                .iter()
+                .map(|var| wasi::parse_env(var))
+                .collect::<Result<_>>()?,
+            args: self.args.clone(),
+            dirs: dirs.chain(writable_dirs).collect::<Result<_>>()?,
+            clock: match self.clock {
+                Some(Clock::Real) if self.deterministic => {
+                    return Err(anyhow!(
+                        "--clock real can't be restarted by --deterministic, \
+                         use --clock fixed or --clock stepped"
+                    ))
+                }
+                Some(clock) => clock,
+                None if self.deterministic => Clock::Stepped(Duration::from_millis(1)),
+                None => Clock::Real,
+            },
+            random: match self.random {
+                Some(Random::Real) if self.deterministic => {
+                    return Err(anyhow!(
+                        "--random real can't be restarted by --deterministic, \
+                         use --random seed:N"
+                    ))
+                }
+                Some(random) => random,
+                None if self.deterministic => Random::Seeded(0),
+                None => Random::Real,
+            },
+            deterministic: self.deterministic,
+        })
+    }
+}
+
+/// Parse a `[module/]export` or `[module/]function=args` initialization
+/// step, and the module it applies to when it names one.
+fn parse_init(entry: &str) -> Result<(Option<String>, Init)> {
+    let (target, args) = match entry.split_once('=') {
+        Some((target, args)) => (target, Some(args)),
+        None => (entry, None),
+    };
+    let (module, name) = match target.split_once('/') {
+        Some((module, name)) => (Some(module.to_string()), name),
+        None => (None, target),
+    };
+    if name.is_empty() {
+        return Err(anyhow!(
+            "expected [module/]export or [module/]function=args, found {:?}",
+            entry
+        ));
+    }
+    let init = match args {
+        None => Init::Export(name.to_string()),
+        Some(args) => {
+            serde_json::from_str::<Vec<serde_json::Value>>(args)
+                .with_context(|| format!("{} isn't a JSON array of arguments", args))?;
+            Init::Call {
+                function: name.to_string(),
+                args: args.to_string(),
+            }
+        }
+    };
+    Ok((module, init))
+}
+
+/// Parse a `module/option=value` override, checking the option and its
+/// value before any module is loaded.
+fn parse_override(entry: &str) -> Result<(String, String, String)> {
+    let parsed = entry
+        .split_once('=')
+        .and_then(|(target, value)| Some((target.split_once('/')?, value)));
+    match parsed {
+        Some(((module, option), value)) if !module.is_empty() => {
+            FactoryOptions::default().set(option, value)?;
+            Ok((module.to_string(), option.to_string(), value.to_string()))
+        }
+        _ => Err(anyhow!("expected module/option=value, found {:?}", entry)),
+    }
+}
+
+/// Parse a `[module/]function=udf|tvf` override.
+fn parse_kind(entry: &str) -> Result<(String, FunctionKind)> {
+    match entry.split_once('=') {
+        Some((function, kind)) => Ok((function.to_string(), kind.parse()?)),
+        None => Err(anyhow!("expected function=udf|tvf, found {:?}", entry)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_init() {
+        assert_eq!(
+            parse_init("sentimentable/sentiment=[\"warm up\"]").unwrap(),
+            (
+                Some("sentimentable".to_string()),
+                Init::Call {
+                    function: "sentiment".into(),
+                    args: "[\"warm up\"]".into(),
+                }
+            )
+        );
+        assert_eq!(
+            parse_init("wizer.initialize").unwrap(),
+            (None, Init::Export("wizer.initialize".into()))
+        );
+        assert!(parse_init("sentiment=[\"warm up\"").is_err());
+        assert!(parse_init("sentiment={}").is_err());
+        assert!(parse_init("sentimentable/").is_err());
+    }
+
+    fn wasi_args(clock: Option<Clock>, random: Option<Random>, deterministic: bool) -> WasiArgs {
+        WasiArgs {
+            env: Vec::new(),
+            args: Vec::new(),
+            dirs: Vec::new(),
+            writable_dirs: Vec::new(),
+            clock,
+            random,
+            deterministic,
+        }
+    }
+
+    #[test]
+    fn test_parse_override() {
+        assert_eq!(
+            parse_override("geo/fuel=1000").unwrap(),
+            ("geo".to_string(), "fuel".to_string(), "1000".to_string())
+        );
+        assert_eq!(parse_override("geo/env=KEY=a=b").unwrap().2, "KEY=a=b");
+        assert!(parse_override("fuel=1000").is_err());
+        assert!(parse_override("geo/fuel=lots").is_err());
+        assert!(parse_override("geo/speed=11").is_err());
+    }
+
+    #[test]
+    fn test_deterministic_defaults() {
+        let options = wasi_args(None, None, false).options().unwrap();
+        assert_eq!((options.clock, options.random), (Clock::Real, Random::Real));
+
+        let options = wasi_args(None, None, true).options().unwrap();
+        assert_eq!(
+            (options.clock, options.random),
+            (Clock::Stepped(Duration::from_millis(1)), Random::Seeded(0))
+        );
+
+        let options = wasi_args(Some(Clock::Fixed(5)), Some(Random::Seeded(7)), true)
+            .options()
+            .unwrap();
+        assert_eq!(
+            (options.clock, options.random),
+            (Clock::Fixed(5), Random::Seeded(7))
+        );
+    }
+
+    #[test]
+    fn test_real_sources_not_deterministic() {
+        assert!(wasi_args(Some(Clock::Real), None, false).options().is_ok());
+        assert!(wasi_args(Some(Clock::Real), None, true).options().is_err());
+        assert!(wasi_args(None, Some(Random::Real), true).options().is_err());
+    }
+
+    #[test]
+    fn test_deterministic_needs_request_instances() {
+        let config = |args: &[&str]| match Cli::try_parse_from(args).unwrap().command {
+            Command::Serve(serve) => serve.modules.config(),
+            _ => unreachable!(),
+        };
+        assert!(config(&["debugger", "serve", "--deterministic", "m.wasm"]).is_ok());
+        assert!(config(&[
+            "debugger",
+            "serve",
+            "--deterministic",
+            "--instances",
+            "connection",
+            "m.wasm"
+        ])
+        .is_err());
+    }
+}
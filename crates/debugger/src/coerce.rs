@@ -0,0 +1,239 @@
+use std::fmt;
+
+use serde_json::{Map, Number, Value};
+
+use crate::signature::{Signature, WitType};
+
+/// An argument that could not be converted to the type the guest expects.
+#[derive(Debug)]
+pub enum ArgError {
+    Arity {
+        expected: usize,
+        found: usize,
+    },
+    Mismatch {
+        column: usize,
+        name: String,
+        expected: WitType,
+        found: Value,
+    },
+}
+
+impl fmt::Display for ArgError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArgError::Arity { expected, found } => {
+                write!(f, "expected {} arguments, found {}", expected, found)
+            }
+            ArgError::Mismatch {
+                column,
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "column {} ({}): expected {}, found {}",
+                column, name, expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ArgError {}
+
+/// Validate a row's arguments against the function signature, converting
+/// values SingleStore sends in a looser representation (for example BIGINTs
+/// and DOUBLEs encoded as strings) into what the guest will deserialize.
+///
+/// Columns are numbered from 1, since column 0 of every row is the row id.
+pub fn coerce_args(sig: &Signature, args: Vec<Value>) -> Result<Vec<Value>, ArgError> {
+    if args.len() != sig.params.len() {
+        return Err(ArgError::Arity {
+            expected: sig.params.len(),
+            found: args.len(),
+        });
+    }
+
+    args.into_iter()
+        .zip(sig.params.iter())
+        .enumerate()
+        .map(|(i, (arg, (name, ty)))| {
+            coerce(ty, &arg).ok_or_else(|| ArgError::Mismatch {
+                column: i + 1,
+                name: name.clone(),
+                expected: ty.clone(),
+                found: arg,
+            })
+        })
+        .collect()
+}
+
+fn coerce(ty: &WitType, value: &Value) -> Option<Value> {
+    match ty {
+        WitType::Option(inner) => match value {
+            Value::Null => Some(Value::Null),
+            v => coerce(inner, v),
+        },
+        _ if value.is_null() => None,
+
+        WitType::Bool => match value {
+            Value::Bool(b) => Some(Value::Bool(*b)),
+            Value::Number(n) => match n.as_i64() {
+                Some(0) => Some(Value::Bool(false)),
+                Some(1) => Some(Value::Bool(true)),
+                _ => None,
+            },
+            Value::String(s) => match s.to_ascii_lowercase().as_str() {
+                "0" | "false" => Some(Value::Bool(false)),
+                "1" | "true" => Some(Value::Bool(true)),
+                _ => None,
+            },
+            _ => None,
+        },
+
+        WitType::U8 => unsigned(value, u8::MAX.into()),
+        WitType::U16 => unsigned(value, u16::MAX.into()),
+        WitType::U32 => unsigned(value, u32::MAX.into()),
+        WitType::U64 => unsigned(value, u64::MAX),
+        WitType::S8 => signed(value, i8::MIN.into(), i8::MAX.into()),
+        WitType::S16 => signed(value, i16::MIN.into(), i16::MAX.into()),
+        WitType::S32 => signed(value, i32::MIN.into(), i32::MAX.into()),
+        WitType::S64 => signed(value, i64::MIN, i64::MAX),
+
+        WitType::Float32 | WitType::Float64 => {
+            let f = match value {
+                Value::Number(n) => n.as_f64(),
+                Value::String(s) => s.trim().parse::<f64>().ok(),
+                _ => None,
+            }?;
+            Number::from_f64(f).map(Value::Number)
+        }
+
+        WitType::Char => match value {
+            Value::String(s) if s.chars().count() == 1 => Some(value.clone()),
+            _ => None,
+        },
+
+        WitType::String => match value {
+            Value::String(_) => Some(value.clone()),
+            _ => None,
+        },
+
+        WitType::List(inner) => match value {
+            Value::Array(items) => items
+                .iter()
+                .map(|v| coerce(inner, v))
+                .collect::<Option<Vec<_>>>()
+                .map(Value::Array),
+            // JSON columns arrive as strings
+            Value::String(s) => match serde_json::from_str::<Value>(s).ok()? {
+                v @ Value::Array(_) => coerce(ty, &v),
+                _ => None,
+            },
+            _ => None,
+        },
+
+        WitType::Tuple(types) => match value {
+            Value::Array(items) if items.len() == types.len() => items
+                .iter()
+                .zip(types)
+                .map(|(v, t)| coerce(t, v))
+                .collect::<Option<Vec<_>>>()
+                .map(Value::Array),
+            _ => None,
+        },
+
+        WitType::Record(record) => {
+            let fields = match value {
+                Value::Object(fields) => fields.clone(),
+                Value::String(s) => match serde_json::from_str::<Value>(s).ok()? {
+                    Value::Object(fields) => fields,
+                    _ => return None,
+                },
+                _ => return None,
+            };
+            record
+                .fields
+                .iter()
+                .map(|(name, ty)| {
+                    let key = name.replace('-', "_");
+                    let v = fields.get(&key).or_else(|| fields.get(name))?;
+                    Some((key, coerce(ty, v)?))
+                })
+                .collect::<Option<Map<_, _>>>()
+                .map(Value::Object)
+        }
+
+        // leave anything we don't model for the guest to deal with
+        WitType::Other(_) => Some(value.clone()),
+    }
+}
+
+fn unsigned(value: &Value, max: u64) -> Option<Value> {
+    let n = match value {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.trim().parse::<u64>().ok(),
+        _ => None,
+    }?;
+    (n <= max).then(|| Value::from(n))
+}
+
+fn signed(value: &Value, min: i64, max: i64) -> Option<Value> {
+    let n = match value {
+        Value::Number(n) => n.as_i64(),
+        Value::String(s) => s.trim().parse::<i64>().ok(),
+        _ => None,
+    }?;
+    (min <= n && n <= max).then(|| Value::from(n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn sig(params: Vec<WitType>) -> Signature {
+        Signature {
+            name: "f".into(),
+            params: params
+                .into_iter()
+                .enumerate()
+                .map(|(i, t)| (format!("p{}", i), t))
+                .collect(),
+            result: WitType::String,
+        }
+    }
+
+    #[test]
+    fn test_numbers_from_strings() {
+        let s = sig(vec![WitType::S64, WitType::U32, WitType::Float64]);
+        let out = coerce_args(&s, vec![json!("-12"), json!("7"), json!("1.5")]).unwrap();
+        assert_eq!(out, vec![json!(-12), json!(7), json!(1.5)]);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let s = sig(vec![WitType::S32, WitType::U8]);
+        let err = coerce_args(&s, vec![json!(1), json!(256)]).unwrap_err();
+        assert_eq!(err.to_string(), "column 2 (p1): expected u8, found 256");
+    }
+
+    #[test]
+    fn test_null_requires_option() {
+        let s = sig(vec![WitType::String]);
+        assert!(coerce_args(&s, vec![Value::Null]).is_err());
+
+        let s = sig(vec![WitType::Option(Box::new(WitType::String))]);
+        assert_eq!(
+            coerce_args(&s, vec![Value::Null]).unwrap(),
+            vec![Value::Null]
+        );
+    }
+
+    #[test]
+    fn test_arity() {
+        let s = sig(vec![WitType::String, WitType::String]);
+        let err = coerce_args(&s, vec![json!("a")]).unwrap_err();
+        assert_eq!(err.to_string(), "expected 2 arguments, found 1");
+    }
+}
@@ -0,0 +1,75 @@
+//! Ahead-of-time compilation of modules, which otherwise are compiled every
+//! time the debugger starts.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::time::Instant;
+
+use crate::cli::CompileArgs;
+use crate::handle::{self, FactoryOptions, HandleFactory};
+
+/// Compile every module to a `.cwasm` file next to it.
+pub fn run(args: &CompileArgs) -> Result<()> {
+    let options = FactoryOptions {
+        limits: args.limits.limits(),
+        profiler: args.profiler,
+        ..FactoryOptions::default()
+    };
+    for wasm_path in &args.modules {
+        let started = Instant::now();
+        let compiled = HandleFactory::precompile(wasm_path, &options)
+            .with_context(|| format!("failed to compile {}", wasm_path.display()))?;
+        let cwasm_path = handle::precompiled_path(wasm_path);
+        fs::write(&cwasm_path, compiled)
+            .with_context(|| format!("failed to write {}", cwasm_path.display()))?;
+        println!(
+            "compiled {} to {} in {:.1?}",
+            wasm_path.display(),
+            cwasm_path.display(),
+            started.elapsed()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::LimitArgs;
+    use crate::handle::Profiler;
+    use std::path::PathBuf;
+
+    fn args(modules: Vec<PathBuf>) -> CompileArgs {
+        CompileArgs {
+            modules,
+            profiler: Profiler::None,
+            limits: LimitArgs {
+                fuel: Some(1000),
+                timeout_ms: None,
+                max_memory: None,
+                max_table_elements: None,
+            },
+        }
+    }
+
+    #[test]
+    fn test_compile() {
+        let dir = std::env::temp_dir().join(format!("debugger-compile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let wasm_path = dir.join("module.wasm");
+        fs::write(&wasm_path, r#"(module (func (export "f")))"#).unwrap();
+
+        run(&args(vec![wasm_path.clone()])).unwrap();
+        let cwasm_path = handle::precompiled_path(&wasm_path);
+        assert!(!fs::read(&cwasm_path).unwrap().is_empty());
+
+        let missing = dir.join("missing.wasm");
+        let err = run(&args(vec![missing.clone()])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("failed to compile {}", missing.display())
+        );
+        assert!(!handle::precompiled_path(&missing).exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
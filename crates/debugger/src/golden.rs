@@ -0,0 +1,279 @@
+//! Golden-file tests: calls with the results or errors they should produce,
+//! run against a compiled module.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Deserializer};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use crate::cli::TestArgs;
+use crate::coerce;
+use crate::handle::{FactoryOptions, HandleFactory};
+use crate::signature::{handler_name, Signatures};
+
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct Case {
+    #[serde(default)]
+    name: Option<String>,
+    function: String,
+    #[serde(default)]
+    args: Vec<Value>,
+    /// The result the call should return. Present even when it's `null`.
+    #[serde(default, deserialize_with = "present")]
+    expected: Option<Value>,
+    /// Text the error message of a call that should fail must contain.
+    #[serde(default)]
+    error: Option<String>,
+}
+
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+impl Case {
+    fn label(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => {
+                let args: Vec<_> = self.args.iter().map(Value::to_string).collect();
+                format!("{}({})", self.function, args.join(", "))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CaseFile {
+    #[serde(default)]
+    case: Vec<Case>,
+}
+
+/// Cases are read from a TOML file of `[[case]]` tables, or a JSONL file
+/// with a case on each line.
+fn load_cases(path: &Path) -> Result<Vec<Case>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let cases: Vec<Case> = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => {
+            toml::from_str::<CaseFile>(&text)
+                .with_context(|| path.display().to_string())?
+                .case
+        }
+        Some("jsonl") => text
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).with_context(|| format!("{}:{}", path.display(), i + 1))
+            })
+            .collect::<Result<_>>()?,
+        _ => {
+            return Err(anyhow!(
+                "{}: expected a .toml or .jsonl file",
+                path.display()
+            ))
+        }
+    };
+
+    for case in &cases {
+        if case.expected.is_some() == case.error.is_some() {
+            return Err(anyhow!(
+                "{}: {} needs either an expected result or an expected error",
+                path.display(),
+                case.label()
+            ));
+        }
+    }
+    Ok(cases)
+}
+
+/// Run every case on a fresh instance of the module, and fail if any of them
+/// doesn't produce what it expects.
+pub fn run(args: &TestArgs) -> Result<()> {
+    let factory = HandleFactory::new(
+        &args.wasm,
+        FactoryOptions {
+            limits: args.limits.limits(),
+            wasi: args.wasi.options()?,
+            ..FactoryOptions::default()
+        },
+    )?;
+    let signatures = match &args.wit {
+        Some(wit_path) => Some(Signatures::from_file(wit_path)?),
+        None => None,
+    };
+    let cases = load_cases(&args.cases)?;
+
+    let mut failed = 0;
+    for case in &cases {
+        let outcome = call(&factory, signatures.as_ref(), case);
+        let failure = match (&outcome, &case.expected, &case.error) {
+            (Ok(actual), Some(expected), _) if same_value(expected, actual) => None,
+            (Ok(actual), Some(expected), _) => Some(diff_lines(
+                &serde_json::to_string_pretty(expected)?,
+                &serde_json::to_string_pretty(actual)?,
+            )),
+            (Ok(actual), None, Some(error)) => Some(vec![format!(
+                "expected an error containing {:?}, got {}",
+                error, actual
+            )]),
+            (Err(err), _, Some(error)) if format!("{:#}", err).contains(error.as_str()) => None,
+            (Err(err), _, Some(error)) => Some(vec![format!(
+                "expected an error containing {:?}, got: {:#}",
+                error, err
+            )]),
+            (Err(err), _, None) => Some(vec![format!("error: {:#}", err)]),
+            (Ok(_), None, None) => unreachable!("checked when loading cases"),
+        };
+
+        match failure {
+            None => println!("ok   {}", case.label()),
+            Some(lines) => {
+                failed += 1;
+                println!("FAIL {}", case.label());
+                for line in lines {
+                    println!("  {}", line);
+                }
+            }
+        }
+    }
+
+    println!("{} passed, {} failed", cases.len() - failed, failed);
+    if failed > 0 {
+        return Err(anyhow!("{} of {} cases failed", failed, cases.len()));
+    }
+    Ok(())
+}
+
+fn call(factory: &HandleFactory, signatures: Option<&Signatures>, case: &Case) -> Result<Value> {
+    let mut args = case.args.clone();
+    if let Some(signatures) = signatures {
+        let sig = signatures
+            .get(&case.function)
+            .ok_or_else(|| anyhow!("function {} is not declared in the WIT file", case.function))?;
+        args = coerce::coerce_args(sig, args)?;
+    }
+
+    let mut handler = factory.make_handler()?;
+    handler.set_echo(false);
+    let output = handler.handle_json(handler_name(&case.function), serde_json::to_vec(&args)?)?;
+    Ok(serde_json::from_slice(&output)?)
+}
+
+/// Whether a result is what a case expects. Numbers are compared by value,
+/// so `1024` in a golden file matches a guest returning `1024.0`.
+fn same_value(expected: &Value, actual: &Value) -> bool {
+    match (expected, actual) {
+        // integers are compared exactly, since large ones aren't as floats
+        (Value::Number(a), Value::Number(b)) if a.is_f64() || b.is_f64() => {
+            a.as_f64() == b.as_f64()
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_value(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(key, a)| matches!(b.get(key), Some(b) if same_value(a, b)))
+        }
+        _ => expected == actual,
+    }
+}
+
+/// A line diff of two texts, with `-` marking expected lines and `+` actual
+/// ones.
+fn diff_lines(expected: &str, actual: &str) -> Vec<String> {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+
+    // lengths of the longest common subsequences of every pair of suffixes
+    let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lcs[i][j] = if a[i] == b[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        if i < a.len() && j < b.len() && a[i] == b[j] {
+            lines.push(format!("  {}", a[i]));
+            i += 1;
+            j += 1;
+        } else if i < a.len() && (j == b.len() || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            lines.push(format!("- {}", a[i]));
+            i += 1;
+        } else {
+            lines.push(format!("+ {}", b[j]));
+            j += 1;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_toml_cases() {
+        let file: CaseFile = toml::from_str(
+            r#"
+            [[case]]
+            function = "power_of"
+            args = [2, 10]
+            expected = 1024
+
+            [[case]]
+            name = "zero"
+            function = "divide"
+            args = [1, 0]
+            error = "divide by zero"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(file.case[0].expected, Some(json!(1024)));
+        assert_eq!(file.case[0].label(), "power_of(2, 10)");
+        assert_eq!(file.case[1].error.as_deref(), Some("divide by zero"));
+        assert_eq!(file.case[1].label(), "zero");
+    }
+
+    #[test]
+    fn test_expected_null() {
+        let case: Case = serde_json::from_str(r#"{"function": "f", "expected": null}"#).unwrap();
+        assert_eq!(case.expected, Some(Value::Null));
+        let case: Case = serde_json::from_str(r#"{"function": "f", "error": "x"}"#).unwrap();
+        assert_eq!(case.expected, None);
+    }
+
+    #[test]
+    fn test_same_value() {
+        assert!(same_value(&json!(1024), &json!(1024.0)));
+        assert!(same_value(&json!(-1), &json!(-1.0)));
+        assert!(same_value(
+            &json!({"rows": [1, 2.5], "ok": true}),
+            &json!({"ok": true, "rows": [1.0, 2.5]})
+        ));
+        assert!(!same_value(&json!(1024), &json!(1024.5)));
+        assert!(!same_value(&json!(u64::MAX), &json!(u64::MAX - 1)));
+        assert!(!same_value(&json!([1]), &json!([1, 2])));
+        assert!(!same_value(&json!({"a": 1}), &json!({"b": 1})));
+        assert!(!same_value(&json!(1), &json!("1")));
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        assert_eq!(
+            diff_lines("[\n  1,\n  2\n]", "[\n  1,\n  3\n]"),
+            vec!["  [", "    1,", "-   2", "+   3", "  ]"]
+        );
+    }
+}
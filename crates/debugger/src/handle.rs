@@ -1,13 +1,149 @@
-use anyhow::Result;
-use wasmtime::{Config, Engine, Linker, Module, Store};
+use anyhow::{anyhow, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use wasi_common::pipe::WritePipe;
+use wasmtime::{Config, Engine, Instance, Linker, Module, ProfilingStrategy, Store, Trap};
 use wasmtime_wasi;
 use wit_bindgen_wasmtime;
 
-wit_bindgen_wasmtime::import!("debugger.wit");
+use crate::attach::Waiter;
+use crate::limits::{self, EpochTicker, LimitKind, Limiter, Limits};
+use crate::signature::{handler_name, Signature, Signatures};
+use crate::snapshot::Snapshot;
+use crate::stats::Histogram;
+use crate::wasi::{self, Clock, Preopen, Random, WasiOptions};
+
+// debugger.wit is what guests export, but guests built before the catalog
+// was added don't export `list-handlers`, so it's bound on its own and only
+// when the guest has it
+wit_bindgen_wasmtime::import!({
+    src["debugger"]: "handle-json: func(name: string, json: list<u8>) -> list<u8>"
+});
+wit_bindgen_wasmtime::import!({
+    src["catalog"]: "list-handlers: func() -> string"
+});
 
 struct Context {
     wasi: wasmtime_wasi::WasiCtx,
     debugger_state: debugger::DebuggerData,
+    catalog_state: catalog::CatalogData,
+    limiter: Limiter,
+}
+
+/// How guest instances are configured, shared by every module served unless
+/// overridden for one of them.
+#[derive(Clone, Debug, Default)]
+pub struct FactoryOptions {
+    pub limits: Limits,
+    pub wasi: WasiOptions,
+    /// Pause before every call until a native debugger attaches.
+    pub wait: Option<Arc<Waiter>>,
+    pub profiler: Profiler,
+    /// How to initialize an instance before snapshotting it. When there are
+    /// any steps, every new instance starts from the snapshot.
+    pub init: Vec<Init>,
+    /// Load the artifact `debugger compile` wrote next to the module, when
+    /// it's up to date, rather than compiling the module.
+    pub precompiled: bool,
+}
+
+impl FactoryOptions {
+    /// Override one option, named like its command line flag: `fuel`,
+    /// `timeout-ms`, `max-memory`, `max-table-elements`, `profiler`, `clock`
+    /// and `random` replace the value, while `env`, `arg`, `dir` and
+    /// `dir-rw` add to it.
+    pub fn set(&mut self, option: &str, value: &str) -> Result<()> {
+        let invalid = || format!("invalid {} {:?}", option, value);
+        match option {
+            "fuel" => self.limits.fuel = Some(value.parse().with_context(invalid)?),
+            "timeout-ms" => {
+                let millis = value.parse().with_context(invalid)?;
+                self.limits.timeout = Some(Duration::from_millis(millis));
+            }
+            "max-memory" => self.limits.max_memory = Some(value.parse().with_context(invalid)?),
+            "max-table-elements" => {
+                self.limits.max_table_elements = Some(value.parse().with_context(invalid)?)
+            }
+            "profiler" => self.profiler = value.parse()?,
+            "env" => self.wasi.env.push(wasi::parse_env(value)?),
+            "arg" => self.wasi.args.push(value.to_string()),
+            "dir" => self.wasi.dirs.push(Preopen::parse(value, false)?),
+            "dir-rw" => self.wasi.dirs.push(Preopen::parse(value, true)?),
+            "clock" => self.wasi.clock = value.parse()?,
+            "random" => self.wasi.random = value.parse()?,
+            _ => {
+                return Err(anyhow!(
+                    "unknown option {:?}, expected fuel, timeout-ms, max-memory, \
+                     max-table-elements, profiler, env, arg, dir, dir-rw, clock or random",
+                    option
+                ))
+            }
+        }
+        if self.wasi.deterministic
+            && (self.wasi.clock == Clock::Real || self.wasi.random == Random::Real)
+        {
+            return Err(anyhow!(
+                "{} {} can't be restarted by --deterministic",
+                option,
+                value
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// A step initializing an instance before it's snapshotted.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Init {
+    /// Call an exported function taking no arguments and returning nothing,
+    /// such as `wizer.initialize`.
+    Export(String),
+    /// Call a handler with a JSON array of arguments, to warm up whatever it
+    /// initializes on first use.
+    Call { function: String, args: String },
+}
+
+/// Which of wasmtime's profiling strategies to compile guests with, so an
+/// external profiler can name the JIT-compiled functions it samples.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Profiler {
+    None,
+    /// Write a `jit-<pid>.dump` file for `perf inject --jit`.
+    JitDump,
+    /// Register the JIT code with Intel VTune.
+    VTune,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Profiler::None
+    }
+}
+
+impl FromStr for Profiler {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Profiler::None),
+            "jitdump" => Ok(Profiler::JitDump),
+            "vtune" => Ok(Profiler::VTune),
+            "perfmap" => Err(anyhow!(
+                "perfmap isn't available in wasmtime 0.35, use jitdump with perf instead"
+            )),
+            _ => Err(anyhow!(
+                "unknown profiler {:?}, expected none, jitdump or vtune",
+                s
+            )),
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -15,66 +151,1068 @@ pub struct HandleFactory {
     engine: Engine,
     linker: Linker<Context>,
     module: Module,
+    wasm_path: PathBuf,
+    options: FactoryOptions,
+    snapshot: Option<Arc<Snapshot>>,
+    /// Shared with the factories reloaded from this one, which reuse its
+    /// engine.
+    ticker: Option<Arc<EpochTicker>>,
 }
 
 impl HandleFactory {
-    fn default_config() -> Result<Config> {
+    fn default_config(options: &FactoryOptions) -> Result<Config> {
         let mut config = Config::new();
         config.debug_info(true);
         config.cache_config_load_default()?;
         config.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Enable);
+        config.consume_fuel(options.limits.fuel.is_some());
+        config.epoch_interruption(options.limits.timeout.is_some());
+        config.profiler(match options.profiler {
+            Profiler::None => ProfilingStrategy::None,
+            Profiler::JitDump => ProfilingStrategy::JitDump,
+            Profiler::VTune => ProfilingStrategy::VTune,
+        })?;
         Ok(config)
     }
 
-    fn default_wasi() -> wasmtime_wasi::WasiCtx {
-        wasmtime_wasi::sync::WasiCtxBuilder::new()
-            .inherit_stdout()
-            .inherit_stderr()
-            .build()
+    fn default_wasi(&self, output: &Output) -> Result<wasmtime_wasi::WasiCtx> {
+        self.options.wasi.build(
+            &self.wasm_path,
+            Box::new(WritePipe::from_shared(output.stdout.clone())),
+            Box::new(WritePipe::from_shared(output.stderr.clone())),
+        )
     }
 
-    pub fn new(wasm_path: &str) -> Result<Self> {
-        let engine = Engine::new(&Self::default_config()?)?;
-        let module = Module::from_file(&engine, wasm_path)?;
+    pub fn new(wasm_path: impl AsRef<Path>, options: FactoryOptions) -> Result<Self> {
+        let wasm_path = wasm_path.as_ref().to_path_buf();
+        let engine = Engine::new(&Self::default_config(&options)?)?;
+        let module = Self::load_module(&engine, &wasm_path, options.precompiled)?;
+
+        let ticker = options
+            .limits
+            .timeout
+            .map(|_| Arc::new(EpochTicker::start(engine.clone())));
 
         let mut linker = Linker::new(&engine);
         wasmtime_wasi::add_to_linker(&mut linker, |cx: &mut Context| &mut cx.wasi)?;
         debugger::Debugger::add_to_linker(&mut linker, |cx: &mut Context| &mut cx.debugger_state)?;
 
-        Ok(Self {
+        Self {
             engine,
             linker,
             module,
-        })
+            wasm_path,
+            options,
+            snapshot: None,
+            ticker,
+        }
+        .with_snapshot()
+    }
+
+    /// Run the initialization steps on an instance and snapshot it, if there
+    /// are any.
+    fn with_snapshot(mut self) -> Result<Self> {
+        self.snapshot = None;
+        if self.options.init.is_empty() {
+            return Ok(self);
+        }
+
+        let mut handler = self.make_handler()?;
+        // initializing isn't a call anyone wants to step through, nor one
+        // the per-row limits are meant for
+        handler.wait = None;
+        handler.lift_limits()?;
+        for init in &self.options.init {
+            match init {
+                Init::Export(name) => {
+                    handler.reset_limits()?;
+                    let init = handler
+                        .linked
+                        .get_typed_func::<(), (), _>(&mut handler.store, name)?;
+                    init.call(&mut handler.store, ()).with_context(|| {
+                        format!(
+                            "failed to initialize {} with {}",
+                            self.wasm_path.display(),
+                            name
+                        )
+                    })?;
+                }
+                Init::Call { function, args } => {
+                    handler
+                        .handle_json(function.clone(), args.clone().into_bytes())
+                        .with_context(|| {
+                            format!(
+                                "failed to warm up {} with {}({})",
+                                self.wasm_path.display(),
+                                function,
+                                args
+                            )
+                        })?;
+                }
+            }
+        }
+        self.snapshot = Some(Arc::new(Snapshot::capture(
+            &mut handler.store,
+            &handler.linked,
+        )));
+        Ok(self)
+    }
+
+    /// Compile a module ahead of time, for engines configured with
+    /// `options`, into an artifact [`HandleFactory::new`] loads instead of
+    /// compiling the module when it finds it at [`precompiled_path`] and
+    /// [`FactoryOptions::precompiled`] is set.
+    pub fn precompile(wasm_path: &Path, options: &FactoryOptions) -> Result<Vec<u8>> {
+        let engine = Engine::new(&Self::default_config(options)?)?;
+        Module::from_file(&engine, wasm_path)?.serialize()
+    }
+
+    /// Load the module's precompiled artifact when asked to and there's one
+    /// at least as recent as the module, and compile the module otherwise.
+    fn load_module(engine: &Engine, wasm_path: &Path, precompiled: bool) -> Result<Module> {
+        if !precompiled {
+            return Module::from_file(engine, wasm_path);
+        }
+        let cwasm_path = precompiled_path(wasm_path);
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        match (modified(&cwasm_path), modified(wasm_path)) {
+            (Some(compiled), Some(source)) if compiled >= source => {
+                // Safety: deserializing trusts the artifact to be native code
+                // wasmtime produced. It checks the artifact was compiled by
+                // this version of wasmtime with compatible settings, and it's
+                // up to whoever put it next to the module that it came from
+                // `debugger compile`.
+                match unsafe { Module::deserialize_file(engine, &cwasm_path) } {
+                    Ok(module) => return Ok(module),
+                    Err(err) => warn(format!(
+                        "ignoring {}, compiling {} instead: {:#}",
+                        cwasm_path.display(),
+                        wasm_path.display(),
+                        err
+                    )),
+                }
+            }
+            (Some(_), Some(_)) => warn(format!(
+                "ignoring {}, which is older than {}",
+                cwasm_path.display(),
+                wasm_path.display()
+            )),
+            (Some(_), None) => warn(format!(
+                "ignoring {}, since the modification time of {} can't be read",
+                cwasm_path.display(),
+                wasm_path.display()
+            )),
+            (None, _) => warn(format!(
+                "no precompiled {}, compiling {}",
+                cwasm_path.display(),
+                wasm_path.display()
+            )),
+        }
+        Module::from_file(engine, wasm_path)
+    }
+
+    /// The memory instances are initialized with, when they start from a
+    /// snapshot.
+    pub fn snapshot_size(&self) -> Option<usize> {
+        self.snapshot.as_ref().map(|snapshot| snapshot.size())
+    }
+
+    pub fn options(&self) -> &FactoryOptions {
+        &self.options
+    }
+
+    pub fn wasm_path(&self) -> &Path {
+        &self.wasm_path
+    }
+
+    /// Load the module from disk again, reusing the engine and linker.
+    pub fn reload(&self) -> Result<Self> {
+        let module = Self::load_module(&self.engine, &self.wasm_path, self.options.precompiled)?;
+        Self {
+            module,
+            ..self.clone()
+        }
+        .with_snapshot()
     }
 
     pub fn make_handler(&self) -> Result<Handler> {
+        let output = Output::default();
         let mut store = Store::new(
             &self.engine,
             Context {
-                wasi: Self::default_wasi(),
+                wasi: self.default_wasi(&output)?,
                 debugger_state: debugger::DebuggerData::default(),
+                catalog_state: catalog::CatalogData::default(),
+                limiter: Limiter::new(&self.options.limits),
             },
         );
+        store.limiter(|cx: &mut Context| &mut cx.limiter);
         let linked = self.linker.instantiate(&mut store, &self.module)?;
+        if let Some(snapshot) = &self.snapshot {
+            snapshot.restore(&mut store, &linked)?;
+        }
         let instance = debugger::Debugger::new(&mut store, &linked, |cx: &mut Context| {
             &mut cx.debugger_state
         })?;
+        let catalog = match linked.get_func(&mut store, "list-handlers") {
+            Some(_) => Some(catalog::Catalog::new(
+                &mut store,
+                &linked,
+                |cx: &mut Context| &mut cx.catalog_state,
+            )?),
+            None => None,
+        };
+
+        Ok(Handler {
+            store,
+            instance,
+            catalog,
+            linked,
+            output,
+            captured: CapturedOutput::default(),
+            echo: true,
+            limits: self.options.limits.clone(),
+            wait: self.options.wait.clone(),
+            wasi: self.options.wasi.clone(),
+            trapped: false,
+        })
+    }
+}
 
-        Ok(Handler { store, instance })
+/// Warn in the server's log when serving, where modules are reloaded long
+/// after startup, and on stderr otherwise.
+fn warn(message: String) {
+    if tide::log::max_level() == tide::log::LevelFilter::Off {
+        eprintln!("warning: {}", message);
+    } else {
+        tide::log::warn!("{}", message);
     }
 }
 
+/// Where `debugger compile` writes the precompiled artifact of a module, and
+/// where [`HandleFactory`] looks for it: next to the module, with a `.cwasm`
+/// extension.
+pub fn precompiled_path(wasm_path: &Path) -> PathBuf {
+    wasm_path.with_extension("cwasm")
+}
+
 pub struct Handler {
     store: Store<Context>,
     instance: debugger::Debugger<Context>,
+    /// The guest's `list-handlers` export, if it was built with a macro that
+    /// records its handlers.
+    catalog: Option<catalog::Catalog<Context>>,
+    /// The raw instance behind the bindings, for inspecting its exports.
+    linked: Instance,
+    output: Output,
+    captured: CapturedOutput,
+    limits: Limits,
+    wait: Option<Arc<Waiter>>,
+    wasi: WasiOptions,
+    trapped: bool,
+    echo: bool,
+}
+
+/// A method of the guest's debug handler, described in rust terms.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HandlerInfo {
+    pub name: String,
+    pub params: Vec<ParamInfo>,
+    pub result: String,
+}
+
+impl From<&Signature> for HandlerInfo {
+    /// A handler as its WIT signature declares it, with WIT types rather than
+    /// rust ones.
+    fn from(sig: &Signature) -> Self {
+        Self {
+            name: handler_name(&sig.name),
+            params: sig
+                .params
+                .iter()
+                .map(|(name, ty)| ParamInfo {
+                    name: name.clone(),
+                    ty: ty.to_string(),
+                })
+                .collect(),
+            result: sig.result.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ParamInfo {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: String,
 }
 
 impl Handler {
     pub fn handle_json(&mut self, name: String, json: Vec<u8>) -> Result<Vec<u8>> {
-        match self.instance.handle_json(&mut self.store, &name, &json) {
+        if let Some(waiter) = &self.wait {
+            waiter.wait(&name);
+        }
+        self.reset_limits()?;
+        let res = self.instance.handle_json(&mut self.store, &name, &json);
+
+        // the guest's output is captured so it can be tied to the row that
+        // produced it, but it still belongs in the terminal
+        self.captured = self.output.take();
+        if self.echo {
+            print!("{}", self.captured.stdout);
+            eprint!("{}", self.captured.stderr);
+        }
+
+        match res {
             Ok(res) => Ok(res),
-            Err(err) => Err(err.into()),
+            Err(trap) => {
+                let stderr = self.captured.stderr.clone();
+                Err(self.trap_error(&trap, &stderr).into())
+            }
+        }
+    }
+
+    /// Restart a deterministic guest's clocks and random numbers before it
+    /// computes the row with `row_id`, seeding them with `seed` rather than
+    /// the configured seed when given.
+    pub fn restart_wasi(&mut self, seed: Option<u64>, row_id: &serde_json::Value) {
+        self.wasi
+            .restart(&mut self.store.data_mut().wasi, seed, row_id);
+    }
+
+    /// Mark the instance as no longer fit for calls after `trap`, and
+    /// describe what went wrong, blaming a limit when one was exceeded.
+    fn trap_error(&mut self, trap: &Trap, stderr: &str) -> GuestError {
+        self.trapped = true;
+        let mut err = GuestError::from_trap(trap, stderr);
+        if let Some(kind) = self.exceeded_limit(trap) {
+            err.message = format!("exceeded {}: {}", self.limits.describe(kind), err.message);
+            err.kind = kind.to_string();
+        }
+        err
+    }
+
+    /// Give the next call a full tank of fuel and a fresh deadline.
+    fn reset_limits(&mut self) -> Result<()> {
+        if let Some(fuel) = self.limits.fuel {
+            // consuming nothing reports how much fuel is left
+            let remaining = self.store.consume_fuel(0)?;
+            self.store.add_fuel(fuel.saturating_sub(remaining))?;
+        }
+        if let Some(ticks) = self.limits.epoch_deadline() {
+            self.store.set_epoch_deadline(ticks);
+        }
+        self.store.data_mut().limiter.exceeded = None;
+        Ok(())
+    }
+
+    /// Let calls run for as long as they take, dropping the fuel and time
+    /// limits but keeping the memory and table ones.
+    fn lift_limits(&mut self) -> Result<()> {
+        if self.limits.fuel.take().is_some() {
+            self.store.add_fuel(u64::MAX)?;
+        }
+        if self.limits.timeout.take().is_some() {
+            // as far away as it can be without overflowing once the current
+            // epoch is added to it
+            self.store.set_epoch_deadline(u64::MAX / 2);
+        }
+        Ok(())
+    }
+
+    fn exceeded_limit(&mut self, trap: &Trap) -> Option<LimitKind> {
+        if let Some(kind) = self.store.data().limiter.exceeded {
+            return Some(kind);
+        }
+        if self.limits.timeout.is_some() && limits::is_timeout(trap) {
+            return Some(LimitKind::Timeout);
         }
+        if self.limits.fuel.is_some() && self.store.consume_fuel(0).ok() == Some(0) {
+            return Some(LimitKind::Fuel);
+        }
+        None
+    }
+
+    /// The handlers the guest exports, as recorded by the
+    /// `export_debug_handler` macro when it was compiled. Guests built before
+    /// the macro recorded them are described by their WIT signatures, if
+    /// there are any.
+    pub fn list_handlers(&mut self, signatures: Option<&Signatures>) -> Result<Vec<HandlerInfo>> {
+        // listing runs guest code like any call, so it gets the same limits
+        self.reset_limits()?;
+        let res = match &self.catalog {
+            Some(catalog) => catalog.list_handlers(&mut self.store),
+            None => {
+                return Ok(signatures
+                    .into_iter()
+                    .flat_map(|signatures| signatures.iter().map(HandlerInfo::from))
+                    .collect())
+            }
+        };
+        match res {
+            Ok(catalog) => Ok(serde_json::from_str(&catalog)?),
+            Err(trap) => {
+                let stderr = self.output.take().stderr;
+                Err(self.trap_error(&trap, &stderr).into())
+            }
+        }
+    }
+
+    /// Whether a call on this handler has trapped, after which its instance
+    /// can't be trusted with another call.
+    pub fn trapped(&self) -> bool {
+        self.trapped
+    }
+
+    /// The size of each memory the guest exports, in bytes.
+    pub fn memory_usage(&mut self) -> Vec<(String, usize)> {
+        let memories: Vec<_> = self
+            .linked
+            .exports(&mut self.store)
+            .filter_map(|export| {
+                let name = export.name().to_string();
+                export.into_memory().map(|memory| (name, memory))
+            })
+            .collect();
+        memories
+            .into_iter()
+            .map(|(name, memory)| (name, memory.data_size(&self.store)))
+            .collect()
+    }
+
+    /// Whether the guest's output is also written to the debugger's own
+    /// stdout and stderr, which it is by default.
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    /// What the guest wrote to stdout and stderr during the last call.
+    pub fn captured(&self) -> &CapturedOutput {
+        &self.captured
+    }
+
+    /// Forget the output of the last call, so a call that fails before
+    /// reaching the guest isn't blamed for it.
+    pub fn clear_captured(&mut self) {
+        self.captured = CapturedOutput::default();
+    }
+}
+
+/// In-memory pipes standing in for the guest's stdout and stderr.
+#[derive(Default)]
+struct Output {
+    stdout: Arc<RwLock<Vec<u8>>>,
+    stderr: Arc<RwLock<Vec<u8>>>,
+}
+
+impl Output {
+    fn take(&self) -> CapturedOutput {
+        let drain = |pipe: &RwLock<Vec<u8>>| {
+            let mut buf = pipe.write().unwrap();
+            let text = String::from_utf8_lossy(&buf).into_owned();
+            buf.clear();
+            text
+        };
+        CapturedOutput {
+            stdout: drain(&self.stdout),
+            stderr: drain(&self.stderr),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CapturedOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// How long a guest instance is kept around for.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Lifetime {
+    /// A fresh instance for every request.
+    Request,
+    /// Reuse an instance for every request on the same connection.
+    Connection,
+    /// Reuse instances for as long as the debugger runs.
+    Forever,
+}
+
+impl FromStr for Lifetime {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "request" => Ok(Lifetime::Request),
+            "connection" => Ok(Lifetime::Connection),
+            "forever" => Ok(Lifetime::Forever),
+            _ => Err(anyhow!(
+                "unknown instance lifetime {:?}, expected request, connection or forever",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct PoolStats {
+    pub lifetime: Lifetime,
+    pub idle: usize,
+    pub created: u64,
+    pub reused: u64,
+    pub recycled: u64,
+    pub instantiation: Histogram,
+}
+
+struct Idle {
+    key: String,
+    generation: u64,
+    handler: Handler,
+}
+
+/// Hands out handlers for requests, keeping instances alive according to the
+/// configured [`Lifetime`] so guests that keep state between calls behave as
+/// they would in SingleStore.
+pub struct HandlerPool {
+    lifetime: Lifetime,
+    factory: RwLock<HandleFactory>,
+    generation: AtomicU64,
+    idle: Mutex<Vec<Idle>>,
+    created: AtomicU64,
+    reused: AtomicU64,
+    recycled: AtomicU64,
+    /// How long creating each instance took.
+    instantiation: Mutex<Histogram>,
+}
+
+impl HandlerPool {
+    /// Connections come and go without telling us, so cap how many idle
+    /// instances are kept and drop the least recently used ones.
+    const MAX_IDLE: usize = 32;
+
+    pub fn new(factory: HandleFactory, lifetime: Lifetime) -> Self {
+        Self {
+            lifetime,
+            factory: RwLock::new(factory),
+            generation: AtomicU64::new(0),
+            idle: Mutex::new(Vec::new()),
+            created: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+            recycled: AtomicU64::new(0),
+            instantiation: Mutex::new(Histogram::default()),
+        }
+    }
+
+    pub fn factory(&self) -> HandleFactory {
+        self.factory.read().unwrap().clone()
+    }
+
+    /// Swap in a new factory. Idle instances of the old module are dropped,
+    /// and instances currently checked out are dropped when returned.
+    pub fn replace_factory(&self, factory: HandleFactory) {
+        let mut current = self.factory.write().unwrap();
+        *current = factory;
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        self.idle.lock().unwrap().clear();
+    }
+
+    /// Get a handler for a request. `connection` identifies the client
+    /// connection and is only used with [`Lifetime::Connection`].
+    pub fn checkout(self: &Arc<Self>, connection: &str) -> Result<PooledHandler> {
+        let key = match self.lifetime {
+            Lifetime::Request | Lifetime::Forever => "",
+            Lifetime::Connection => connection,
+        };
+        let generation = self.generation.load(Ordering::SeqCst);
+
+        let reused = if self.lifetime == Lifetime::Request {
+            None
+        } else {
+            let mut idle = self.idle.lock().unwrap();
+            idle.iter()
+                .rposition(|i| i.key == key && i.generation == generation)
+                .map(|pos| idle.remove(pos).handler)
+        };
+
+        let handler = match reused {
+            Some(handler) => {
+                self.reused.fetch_add(1, Ordering::Relaxed);
+                handler
+            }
+            None => {
+                let started = Instant::now();
+                let handler = self.factory().make_handler()?;
+                self.instantiation.lock().unwrap().record(started.elapsed());
+                self.created.fetch_add(1, Ordering::Relaxed);
+                handler
+            }
+        };
+
+        Ok(PooledHandler {
+            pool: self.clone(),
+            key: key.into(),
+            generation,
+            handler: Some(handler),
+        })
+    }
+
+    fn checkin(&self, key: String, generation: u64, handler: Handler) {
+        if handler.trapped() {
+            self.recycled.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if self.lifetime == Lifetime::Request
+            || generation != self.generation.load(Ordering::SeqCst)
+        {
+            return;
+        }
+
+        let mut idle = self.idle.lock().unwrap();
+        idle.push(Idle {
+            key,
+            generation,
+            handler,
+        });
+        if idle.len() > Self::MAX_IDLE {
+            idle.remove(0);
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            lifetime: self.lifetime,
+            idle: self.idle.lock().unwrap().len(),
+            created: self.created.load(Ordering::Relaxed),
+            reused: self.reused.load(Ordering::Relaxed),
+            recycled: self.recycled.load(Ordering::Relaxed),
+            instantiation: self.instantiation.lock().unwrap().clone(),
+        }
+    }
+}
+
+/// A handler checked out of a [`HandlerPool`], returned to it when dropped.
+pub struct PooledHandler {
+    pool: Arc<HandlerPool>,
+    key: String,
+    generation: u64,
+    handler: Option<Handler>,
+}
+
+impl Deref for PooledHandler {
+    type Target = Handler;
+
+    fn deref(&self) -> &Handler {
+        self.handler.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledHandler {
+    fn deref_mut(&mut self) -> &mut Handler {
+        self.handler.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledHandler {
+    fn drop(&mut self) {
+        if let Some(handler) = self.handler.take() {
+            self.pool
+                .checkin(std::mem::take(&mut self.key), self.generation, handler);
+        }
+    }
+}
+
+/// A trap raised by the guest, with enough detail to find where it happened.
+#[derive(Debug)]
+pub struct GuestError {
+    pub message: String,
+    pub backtrace: Vec<String>,
+    pub panic: Option<String>,
+    /// What kind of trap this was, e.g. `panic`, `fuel` or
+    /// `memory_out_of_bounds`.
+    pub kind: String,
+}
+
+impl GuestError {
+    fn from_trap(trap: &Trap, stderr: &str) -> Self {
+        // the Display impl of a trap appends the backtrace after the reason
+        let message = trap.to_string().lines().next().unwrap_or_default().into();
+
+        let backtrace = trap
+            .trace()
+            .iter()
+            .map(|frame| {
+                let name = match frame.func_name() {
+                    Some(name) => rustc_demangle::demangle(name).to_string(),
+                    None => format!("<wasm function {}>", frame.func_index()),
+                };
+                let mut line = format!("{}!{}", frame.module_name().unwrap_or("<unknown>"), name);
+                for symbol in frame.symbols() {
+                    if let (Some(file), Some(lineno)) = (symbol.file(), symbol.line()) {
+                        line.push_str(&format!(" at {}:{}", file, lineno));
+                    }
+                }
+                line
+            })
+            .collect();
+
+        let panic = panic_message(stderr);
+        let kind = match trap.trap_code() {
+            // rust panics abort with an unreachable instruction
+            _ if panic.is_some() => "panic".to_string(),
+            Some(code) => snake_case(&format!("{:?}", code)),
+            None if trap.i32_exit_status().is_some() => "exit".to_string(),
+            None => "host".to_string(),
+        };
+
+        Self {
+            message,
+            backtrace,
+            panic,
+            kind,
+        }
+    }
+}
+
+/// `UnreachableCodeReached` becomes `unreachable_code_reached`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            snake.push('_');
+        }
+        snake.push(c.to_ascii_lowercase());
+    }
+    snake
+}
+
+impl fmt::Display for GuestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.panic {
+            Some(panic) => write!(f, "{}: panicked at {}", self.message, panic),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for GuestError {}
+
+/// Pull the message of a rust panic out of the guest's stderr, as
+/// `'msg', src/lib.rs:7:5`. Before rust 1.73 panics are printed as
+/// `thread '<unnamed>' panicked at 'msg', src/lib.rs:7:5`, and since then as
+/// `thread '<unnamed>' panicked at src/lib.rs:7:5:` followed by the message
+/// on the next lines.
+fn panic_message(stderr: &str) -> Option<String> {
+    const MARKER: &str = "panicked at ";
+    let start = stderr.find(MARKER)? + MARKER.len();
+    let rest = &stderr[start..];
+    let end = rest.find("\nnote:").unwrap_or(rest.len());
+    let panic = rest[..end].trim();
+    if panic.starts_with('\'') {
+        return Some(panic.to_string());
+    }
+    let (location, message) = panic.split_once('\n').unwrap_or((panic, ""));
+    Some(format!(
+        "'{}', {}",
+        message.trim(),
+        location.trim_end().trim_end_matches(':')
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::Instance;
+
+    #[test]
+    fn test_panic_message() {
+        let old = "thread '<unnamed>' panicked at 'attempt to divide by zero', src/lib.rs:7:9\n\
+                   note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace\n";
+        let new = "thread '<unnamed>' panicked at src/lib.rs:7:9:\n\
+                   attempt to divide by zero\n\
+                   note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace\n";
+        let expected = Some("'attempt to divide by zero', src/lib.rs:7:9".to_string());
+        assert_eq!(panic_message(old), expected);
+        assert_eq!(panic_message(new), expected);
+        assert_eq!(
+            panic_message("thread 'main' panicked at src/lib.rs:3:5:\nline one\nline two"),
+            Some("'line one\nline two', src/lib.rs:3:5".to_string())
+        );
+        assert_eq!(panic_message("hello from the guest\n"), None);
+    }
+
+    #[test]
+    fn test_snake_case() {
+        assert_eq!(
+            snake_case("UnreachableCodeReached"),
+            "unreachable_code_reached"
+        );
+        assert_eq!(snake_case("MemoryOutOfBounds"), "memory_out_of_bounds");
+        assert_eq!(snake_case("Interrupt"), "interrupt");
+    }
+
+    /// The trap of calling a function that runs into an unreachable
+    /// instruction, which is how rust panics end.
+    fn unreachable_trap() -> Trap {
+        let engine = Engine::default();
+        let module = Module::new(&engine, r#"(module (func (export "f") unreachable))"#).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let f = instance
+            .get_typed_func::<(), (), _>(&mut store, "f")
+            .unwrap();
+        f.call(&mut store, ()).unwrap_err()
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let trap = unreachable_trap();
+        assert_eq!(
+            GuestError::from_trap(&trap, "").kind,
+            "unreachable_code_reached"
+        );
+        let panicked =
+            GuestError::from_trap(&trap, "thread 'main' panicked at src/lib.rs:1:1:\noops");
+        assert_eq!(panicked.kind, "panic");
+        assert_eq!(panicked.panic.as_deref(), Some("'oops', src/lib.rs:1:1"));
+        assert!(!panicked.backtrace.is_empty());
+
+        assert_eq!(GuestError::from_trap(&Trap::i32_exit(1), "").kind, "exit");
+        assert_eq!(
+            GuestError::from_trap(&Trap::new("no such file"), "").kind,
+            "host"
+        );
+    }
+
+    /// A directory of its own for a test to write modules to.
+    fn scratch_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("debugger-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Write a module exporting a function named `export`, along with an
+    /// artifact precompiled from a module exporting `precompiled`, so which
+    /// one got loaded can be told apart.
+    fn write_modules(dir: &Path, export: &str, precompiled: &str) -> (Engine, PathBuf) {
+        let options = FactoryOptions::default();
+        let engine = Engine::new(&HandleFactory::default_config(&options).unwrap()).unwrap();
+        let wat = |name: &str| format!(r#"(module (func (export "{}")))"#, name);
+
+        let wasm_path = dir.join("module.wasm");
+        fs::write(&wasm_path, wat(precompiled)).unwrap();
+        let compiled = HandleFactory::precompile(&wasm_path, &options).unwrap();
+        fs::write(&wasm_path, wat(export)).unwrap();
+        fs::write(precompiled_path(&wasm_path), compiled).unwrap();
+        (engine, wasm_path)
+    }
+
+    #[test]
+    fn test_load_precompiled() {
+        let dir = scratch_dir("load-precompiled");
+        let (engine, wasm_path) = write_modules(&dir, "source", "compiled");
+
+        let module = HandleFactory::load_module(&engine, &wasm_path, true).unwrap();
+        assert!(module.get_export("compiled").is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_precompiled_needs_asking() {
+        let dir = scratch_dir("load-precompiled-unasked");
+        let (engine, wasm_path) = write_modules(&dir, "source", "compiled");
+
+        let module = HandleFactory::load_module(&engine, &wasm_path, false).unwrap();
+        assert!(module.get_export("source").is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_stale_precompiled() {
+        let dir = scratch_dir("load-stale-precompiled");
+        let (engine, wasm_path) = write_modules(&dir, "source", "compiled");
+        fs::File::options()
+            .write(true)
+            .open(precompiled_path(&wasm_path))
+            .unwrap()
+            .set_modified(std::time::UNIX_EPOCH)
+            .unwrap();
+
+        let module = HandleFactory::load_module(&engine, &wasm_path, true).unwrap();
+        assert!(module.get_export("source").is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_invalid_precompiled() {
+        let dir = scratch_dir("load-invalid-precompiled");
+        let (engine, wasm_path) = write_modules(&dir, "source", "compiled");
+        fs::write(precompiled_path(&wasm_path), b"not an artifact").unwrap();
+
+        let module = HandleFactory::load_module(&engine, &wasm_path, true).unwrap();
+        assert!(module.get_export("source").is_some());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_missing_module() {
+        let dir = scratch_dir("load-missing-module");
+        let (engine, wasm_path) = write_modules(&dir, "source", "compiled");
+        fs::remove_file(&wasm_path).unwrap();
+
+        // an artifact without its module isn't loaded on its own
+        assert!(HandleFactory::load_module(&engine, &wasm_path, true).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// A guest with the exports the debugger expects, whose handlers all trap.
+    const TRAPPING_GUEST: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "canonical_abi_realloc") (param i32 i32 i32 i32) (result i32)
+            (i32.const 1024))
+          (func (export "canonical_abi_free") (param i32 i32 i32))
+          (func (export "handle-json") (param i32 i32 i32 i32) (result i32)
+            unreachable)
+          (func (export "list-handlers") (result i32)
+            unreachable))
+    "#;
+
+    fn factory(test: &str, guest: &str) -> HandleFactory {
+        let dir = scratch_dir(test);
+        let wasm_path = dir.join("guest.wasm");
+        fs::write(&wasm_path, guest).unwrap();
+        let factory = HandleFactory::new(&wasm_path, FactoryOptions::default()).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+        factory
+    }
+
+    #[test]
+    fn test_init_snapshot() {
+        // spins for longer than the fuel limit allows a row
+        let guest = TRAPPING_GUEST.replace(
+            r#"(memory (export "memory") 1)"#,
+            r#"(memory (export "memory") 1)
+               (global $calls (export "calls") (mut i32) (i32.const 0))
+               (func (export "init")
+                 (loop $spin
+                   (global.set $calls (i32.add (global.get $calls) (i32.const 1)))
+                   (br_if $spin (i32.lt_u (global.get $calls) (i32.const 1000)))))"#,
+        );
+        let dir = scratch_dir("init-snapshot");
+        let wasm_path = dir.join("guest.wasm");
+        fs::write(&wasm_path, guest).unwrap();
+        let options = FactoryOptions {
+            limits: Limits {
+                fuel: Some(100),
+                ..Limits::default()
+            },
+            init: vec![Init::Export("init".into())],
+            ..FactoryOptions::default()
+        };
+        let factory = HandleFactory::new(&wasm_path, options).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        assert!(factory.snapshot_size().is_some());
+        let mut handler = factory.make_handler().unwrap();
+        let calls = handler
+            .linked
+            .get_global(&mut handler.store, "calls")
+            .unwrap();
+        assert_eq!(calls.get(&mut handler.store).i32(), Some(1000));
+        // calls still get the per-row limits
+        assert_eq!(handler.limits.fuel, Some(100));
+    }
+
+    fn pool(test: &str, lifetime: Lifetime) -> Arc<HandlerPool> {
+        Arc::new(HandlerPool::new(factory(test, TRAPPING_GUEST), lifetime))
+    }
+
+    #[test]
+    fn test_guest_without_catalog() {
+        // built before the macro recorded the handlers it exports
+        let guest = TRAPPING_GUEST.replace(r#"(export "list-handlers")"#, "");
+        let mut handler = factory("without-catalog", &guest).make_handler().unwrap();
+        assert!(handler.list_handlers(None).unwrap().is_empty());
+
+        let dir = scratch_dir("without-catalog-wit");
+        let wit_path = dir.join("guest.wit");
+        fs::write(&wit_path, "power-of: func(base: s32, exp: s32) -> s32").unwrap();
+        let signatures = Signatures::from_file(&wit_path).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        let handlers = handler.list_handlers(Some(&signatures)).unwrap();
+        assert_eq!(handlers.len(), 1);
+        assert_eq!(handlers[0].name, "power_of");
+        assert_eq!(handlers[0].params[1].name, "exp");
+        assert_eq!(handlers[0].params[1].ty, "s32");
+        assert_eq!(handlers[0].result, "s32");
+    }
+
+    #[test]
+    fn test_catalog_trap() {
+        let mut handler = factory("catalog-trap", TRAPPING_GUEST)
+            .make_handler()
+            .unwrap();
+        let err = handler.list_handlers(None).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<GuestError>().unwrap().kind,
+            "unreachable_code_reached"
+        );
+        assert!(handler.trapped());
+    }
+
+    #[test]
+    fn test_pool_reuse() {
+        let requests = pool("pool-request", Lifetime::Request);
+        drop(requests.checkout("a").unwrap());
+        drop(requests.checkout("a").unwrap());
+        let stats = requests.stats();
+        assert_eq!((stats.created, stats.reused, stats.idle), (2, 0, 0));
+
+        let connections = pool("pool-connection", Lifetime::Connection);
+        drop(connections.checkout("a").unwrap());
+        drop(connections.checkout("b").unwrap());
+        drop(connections.checkout("a").unwrap());
+        let stats = connections.stats();
+        assert_eq!((stats.created, stats.reused, stats.idle), (2, 1, 2));
+
+        let forever = pool("pool-forever", Lifetime::Forever);
+        drop(forever.checkout("a").unwrap());
+        drop(forever.checkout("b").unwrap());
+        let stats = forever.stats();
+        assert_eq!((stats.created, stats.reused, stats.idle), (1, 1, 1));
+    }
+
+    #[test]
+    fn test_pool_recycles_trapped() {
+        let pool = pool("pool-trapped", Lifetime::Forever);
+        let mut handler = pool.checkout("a").unwrap();
+        assert!(handler.handle_json("f".into(), b"[]".to_vec()).is_err());
+        assert!(handler.trapped());
+        drop(handler);
+
+        let stats = pool.stats();
+        assert_eq!((stats.recycled, stats.idle), (1, 0));
+        assert!(!pool.checkout("a").unwrap().trapped());
+        assert_eq!(pool.stats().created, 2);
+    }
+
+    #[test]
+    fn test_pool_drops_stale_generation() {
+        let pool = pool("pool-stale", Lifetime::Forever);
+        let checked_out = pool.checkout("a").unwrap();
+        drop(pool.checkout("a").unwrap());
+        assert_eq!(pool.stats().idle, 1);
+
+        pool.replace_factory(pool.factory());
+        // the idle instance goes right away, the checked out one once it's
+        // returned
+        assert_eq!(pool.stats().idle, 0);
+        drop(checked_out);
+        assert_eq!(pool.stats().idle, 0);
+
+        drop(pool.checkout("a").unwrap());
+        let stats = pool.stats();
+        assert_eq!((stats.created, stats.reused, stats.idle), (3, 0, 1));
     }
 }
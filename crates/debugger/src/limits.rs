@@ -0,0 +1,215 @@
+use std::fmt;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use wasmtime::{Engine, ResourceLimiter, Trap, TrapCode};
+
+/// Resource limits applied to every guest call.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// Fuel available to each row; wasmtime charges roughly one unit per
+    /// wasm instruction.
+    pub fuel: Option<u64>,
+    /// Wall-clock time allowed for each row.
+    pub timeout: Option<Duration>,
+    /// Maximum size of a guest's linear memory, in bytes.
+    pub max_memory: Option<usize>,
+    /// Maximum number of elements in any of a guest's tables.
+    pub max_table_elements: Option<u32>,
+}
+
+impl Limits {
+    /// How often the engine's epoch is bumped when a timeout is configured.
+    pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+    /// The number of epoch ticks a call may run for before being interrupted.
+    pub fn epoch_deadline(&self) -> Option<u64> {
+        self.timeout.map(|timeout| {
+            let ticks = timeout.as_millis() / Self::EPOCH_TICK.as_millis();
+            (ticks as u64).max(1)
+        })
+    }
+
+    /// Describe the limit of the given kind, e.g. `fuel limit of 1000 units`.
+    pub fn describe(&self, kind: LimitKind) -> String {
+        match kind {
+            LimitKind::Fuel => format!("fuel limit of {} units", self.fuel.unwrap_or_default()),
+            LimitKind::Timeout => format!("time limit of {:?}", self.timeout.unwrap_or_default()),
+            LimitKind::Memory => format!(
+                "memory limit of {} bytes",
+                self.max_memory.unwrap_or_default()
+            ),
+            LimitKind::Table => format!(
+                "table limit of {} elements",
+                self.max_table_elements.unwrap_or_default()
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitKind {
+    Fuel,
+    Timeout,
+    Memory,
+    Table,
+}
+
+impl fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitKind::Fuel => write!(f, "fuel"),
+            LimitKind::Timeout => write!(f, "timeout"),
+            LimitKind::Memory => write!(f, "memory"),
+            LimitKind::Table => write!(f, "table"),
+        }
+    }
+}
+
+/// Enforces the memory and table limits, remembering which one refused to
+/// grow so a trap that follows can be blamed on it.
+pub struct Limiter {
+    max_memory: Option<usize>,
+    max_table_elements: Option<u32>,
+    pub exceeded: Option<LimitKind>,
+}
+
+impl Limiter {
+    pub fn new(limits: &Limits) -> Self {
+        Self {
+            max_memory: limits.max_memory,
+            max_table_elements: limits.max_table_elements,
+            exceeded: None,
+        }
+    }
+}
+
+impl ResourceLimiter for Limiter {
+    fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
+        match self.max_memory {
+            Some(max) if desired > max => {
+                self.exceeded = Some(LimitKind::Memory);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
+        match self.max_table_elements {
+            Some(max) if desired > max => {
+                self.exceeded = Some(LimitKind::Table);
+                false
+            }
+            _ => true,
+        }
+    }
+}
+
+/// Whether a call trapped because its epoch deadline passed, which wasmtime
+/// reports either as an interrupt or as a trap of its own saying so.
+pub fn is_timeout(trap: &Trap) -> bool {
+    trap.trap_code() == Some(TrapCode::Interrupt)
+        || trap
+            .to_string()
+            .starts_with("epoch deadline reached during execution")
+}
+
+/// Bumps an engine's epoch every [`Limits::EPOCH_TICK`] so calls with an
+/// epoch deadline get interrupted once their time is up. The thread doing so
+/// stops when the ticker is dropped.
+pub struct EpochTicker {
+    _stop: Mutex<Sender<()>>,
+}
+
+impl EpochTicker {
+    pub fn start(engine: Engine) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            // nothing is ever sent, so this only ends once the sender is gone
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(Limits::EPOCH_TICK) {
+                engine.increment_epoch();
+            }
+        });
+        Self {
+            _stop: Mutex::new(stop),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::{Config, Instance, Module, Store};
+
+    #[test]
+    fn test_epoch_deadline() {
+        let limits = |ms| Limits {
+            timeout: Some(Duration::from_millis(ms)),
+            ..Limits::default()
+        };
+        assert_eq!(Limits::default().epoch_deadline(), None);
+        assert_eq!(limits(2000).epoch_deadline(), Some(200));
+        assert_eq!(limits(25).epoch_deadline(), Some(2));
+        // shorter than a tick still gets one
+        assert_eq!(limits(1).epoch_deadline(), Some(1));
+    }
+
+    #[test]
+    fn test_describe() {
+        let limits = Limits {
+            fuel: Some(1000),
+            timeout: Some(Duration::from_secs(2)),
+            max_memory: Some(65536),
+            max_table_elements: Some(10),
+        };
+        assert_eq!(limits.describe(LimitKind::Fuel), "fuel limit of 1000 units");
+        assert_eq!(limits.describe(LimitKind::Timeout), "time limit of 2s");
+        assert_eq!(
+            limits.describe(LimitKind::Memory),
+            "memory limit of 65536 bytes"
+        );
+        assert_eq!(
+            limits.describe(LimitKind::Table),
+            "table limit of 10 elements"
+        );
+    }
+
+    #[test]
+    fn test_limiter() {
+        let mut limiter = Limiter::new(&Limits {
+            max_memory: Some(65536),
+            max_table_elements: Some(10),
+            ..Limits::default()
+        });
+        assert!(limiter.memory_growing(0, 65536, None));
+        assert!(limiter.table_growing(0, 10, None));
+        assert_eq!(limiter.exceeded, None);
+        assert!(!limiter.table_growing(10, 11, None));
+        assert_eq!(limiter.exceeded, Some(LimitKind::Table));
+        assert!(!limiter.memory_growing(65536, 131072, None));
+        assert_eq!(limiter.exceeded, Some(LimitKind::Memory));
+    }
+
+    #[test]
+    fn test_epoch_ticker_interrupts() {
+        let mut config = Config::new();
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config).unwrap();
+        let module =
+            Module::new(&engine, r#"(module (func (export "spin") (loop (br 0))))"#).unwrap();
+        let mut store = Store::new(&engine, ());
+        store.set_epoch_deadline(1);
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let spin = instance
+            .get_typed_func::<(), (), _>(&mut store, "spin")
+            .unwrap();
+
+        let _ticker = EpochTicker::start(engine.clone());
+        let trap = spin.call(&mut store, ()).unwrap_err();
+        assert!(is_timeout(&trap), "{}", trap);
+        assert!(!is_timeout(&Trap::new("no such file")));
+    }
+}
@@ -1,28 +1,98 @@
 use anyhow::Result;
+use clap::Parser;
+use std::sync::Arc;
 
+mod attach;
+mod batch;
+mod bench;
+mod call;
+mod cli;
+mod coerce;
+mod compile;
+mod golden;
 mod handle;
+mod limits;
+mod metrics;
+mod record;
+mod registry;
+mod reload;
+mod repl;
+mod rowdat;
 mod server;
+mod shape;
+mod signature;
+mod snapshot;
+mod sql;
+mod stats;
+mod wasi;
 
-#[async_std::main]
-async fn main() -> Result<()> {
-    let args: Vec<String> = std::env::args().collect();
+use cli::{Cli, Command};
 
-    // print usage if no args
-    if args.len() < 3 {
-        println!("Usage: {} <port> <path/to/foo.wasm>", args[0]);
+#[async_std::main]
+async fn main() {
+    // invalid arguments exit with clap's usage error (2), and anything that
+    // goes wrong afterwards exits with 1
+    let cli = Cli::parse();
+    if let Err(err) = run(cli).await {
+        if !err.is::<call::Reported>() {
+            eprintln!("error: {:#}", err);
+        }
         std::process::exit(1);
     }
+}
 
-    let port_number = args[1].parse::<u16>().unwrap();
-    let wasm_path = args[2].clone();
-    let wit_path = args.get(3);
+async fn run(cli: Cli) -> Result<()> {
+    match cli.command {
+        Command::Serve(args) => {
+            tide::log::with_level(args.log_level);
+            let registry = args.load()?;
+            if args.wait_for_debugger {
+                attach::print_instructions(&registry, args.single_thread)?;
+            }
+            let guest_thread = if args.single_thread {
+                Some(attach::GuestThread::spawn()?)
+            } else {
+                None
+            };
+            if args.print_sql {
+                if args.sql_host.is_none() && args.bind.is_unspecified() {
+                    eprintln!(
+                        "warning: pass --sql-host to say where SingleStoreDB reaches the debugger"
+                    );
+                }
+                let host = args.sql_host();
+                print!("{}", sql::external_functions(&registry, &host)?);
+                println!(
+                    "-- drop them again with the statements at http://{}/sql/drop",
+                    host
+                );
+            }
+            let recorder = match &args.record {
+                Some(path) => Some(record::Recorder::create(path)?),
+                None => None,
+            };
 
-    println!("debugging: {}", wasm_path);
-    if wit_path.is_some() {
-        println!("with wit: {}", wit_path.unwrap());
+            // show where the time went when the server is stopped
+            let registry = Arc::new(registry);
+            let stopped = registry.clone();
+            ctrlc::set_handler(move || {
+                print!("{}", stats::report(&stopped));
+                std::process::exit(0);
+            })?;
+            server::listen_and_serve(args.addr(), registry, recorder, guest_thread).await
+        }
+        Command::Replay(args) => {
+            let registry = args.modules.load()?;
+            record::replay(&args.recording, &registry)
+        }
+        Command::Bench(args) => {
+            let registry = args.modules.load()?;
+            bench::run(&registry, &args)
+        }
+        Command::Call(args) => call::run(&args),
+        Command::Repl(args) => repl::run(&args),
+        Command::Test(args) => golden::run(&args),
+        Command::Sql(args) => sql::run(&args),
+        Command::Compile(args) => compile::run(&args),
     }
-
-    let factory = handle::HandleFactory::new(&wasm_path)?;
-
-    server::listen_and_serve(port_number, factory).await
 }
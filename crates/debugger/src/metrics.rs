@@ -0,0 +1,184 @@
+//! The debugger's statistics in the Prometheus text exposition format.
+
+use std::fmt::Write;
+
+use crate::registry::Registry;
+use crate::stats::Histogram;
+
+/// Render every module's counters and histograms for `/metrics`.
+pub fn render(registry: &Registry) -> String {
+    let functions: Vec<_> = registry
+        .modules()
+        .flat_map(|module| {
+            module
+                .stats
+                .snapshot()
+                .into_iter()
+                .map(move |(function, stats)| (module.name.clone(), function, stats))
+        })
+        .collect();
+    let mut out = Metrics::default();
+
+    out.help(
+        "debugger_requests_total",
+        "counter",
+        "Requests received for each function.",
+    );
+    for (module, function, stats) in &functions {
+        let labels = [("module", module.as_str()), ("function", function)];
+        out.sample("debugger_requests_total", &labels, stats.requests);
+    }
+
+    out.help(
+        "debugger_rows_total",
+        "counter",
+        "Rows processed by each function.",
+    );
+    for (module, function, stats) in &functions {
+        let labels = [("module", module.as_str()), ("function", function)];
+        out.sample("debugger_rows_total", &labels, stats.calls);
+    }
+
+    out.help(
+        "debugger_errors_total",
+        "counter",
+        "Rows that failed, by kind: argument, trap or other.",
+    );
+    for (module, function, stats) in &functions {
+        for (kind, count) in &stats.errors {
+            let labels = [
+                ("module", module.as_str()),
+                ("function", function),
+                ("kind", kind),
+            ];
+            out.sample("debugger_errors_total", &labels, count);
+        }
+    }
+
+    out.help(
+        "debugger_traps_total",
+        "counter",
+        "Guest traps, by kind such as panic, fuel or memory_out_of_bounds.",
+    );
+    for (module, function, stats) in &functions {
+        for (kind, count) in &stats.traps {
+            let labels = [
+                ("module", module.as_str()),
+                ("function", function),
+                ("kind", kind),
+            ];
+            out.sample("debugger_traps_total", &labels, count);
+        }
+    }
+
+    out.help(
+        "debugger_guest_seconds",
+        "histogram",
+        "Time spent in the guest for each row.",
+    );
+    for (module, function, stats) in &functions {
+        let labels = [("module", module.as_str()), ("function", function)];
+        out.histogram("debugger_guest_seconds", &labels, &stats.latency);
+    }
+
+    out.help(
+        "debugger_instantiation_seconds",
+        "histogram",
+        "Time taken to create a guest instance.",
+    );
+    for module in registry.modules() {
+        out.histogram(
+            "debugger_instantiation_seconds",
+            &[("module", &module.name)],
+            &module.pool.stats().instantiation,
+        );
+    }
+
+    out.text
+}
+
+#[derive(Default)]
+struct Metrics {
+    text: String,
+}
+
+impl Metrics {
+    fn help(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.text, "# HELP {} {}", name, help);
+        let _ = writeln!(self.text, "# TYPE {} {}", name, kind);
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        let _ = writeln!(self.text, "{}{} {}", name, format_labels(labels), value);
+    }
+
+    fn histogram(&mut self, name: &str, labels: &[(&str, &str)], histogram: &Histogram) {
+        let bucket = format!("{}_bucket", name);
+        for (bound, count) in histogram.buckets() {
+            let le = bound.as_secs_f64().to_string();
+            let mut labels = labels.to_vec();
+            labels.push(("le", &le));
+            self.sample(&bucket, &labels, count);
+        }
+        let mut labels_inf = labels.to_vec();
+        labels_inf.push(("le", "+Inf"));
+        self.sample(&bucket, &labels_inf, histogram.count());
+        self.sample(
+            &format!("{}_sum", name),
+            labels,
+            histogram.sum().as_secs_f64(),
+        );
+        self.sample(&format!("{}_count", name), labels, histogram.count());
+    }
+}
+
+fn format_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|(name, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", name, value)
+        })
+        .collect();
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        histogram.record(Duration::from_micros(20));
+        histogram.record(Duration::from_secs(2));
+
+        let mut out = Metrics::default();
+        out.histogram("t_seconds", &[("function", "f")], &histogram);
+        let lines: Vec<_> = out.text.lines().collect();
+        assert_eq!(lines[0], r#"t_seconds_bucket{function="f",le="0.00001"} 0"#);
+        assert_eq!(
+            lines[1],
+            r#"t_seconds_bucket{function="f",le="0.000025"} 1"#
+        );
+        assert_eq!(lines[16], r#"t_seconds_bucket{function="f",le="+Inf"} 2"#);
+        assert_eq!(lines[17], r#"t_seconds_sum{function="f"} 2.00002"#);
+        assert_eq!(lines[18], r#"t_seconds_count{function="f"} 2"#);
+    }
+
+    #[test]
+    fn test_labels() {
+        assert_eq!(
+            format_labels(&[("module", "a\"b"), ("kind", "x\\y")]),
+            r#"{module="a\"b",kind="x\\y"}"#
+        );
+        assert_eq!(format_labels(&[]), "");
+    }
+}
@@ -0,0 +1,292 @@
+//! Recording requests to a JSONL file, and replaying them to check that a
+//! module still produces the same results.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::batch::{self, Options, Outcome, Row, RowError};
+use crate::registry::Registry;
+
+/// One recorded request. ROWDAT_1 payloads are recorded decoded, so every
+/// entry can be replayed as JSON.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub timestamp_ms: u64,
+    pub module: String,
+    pub function: String,
+    #[serde(default)]
+    pub options: Options,
+    pub payload: Vec<Row>,
+    #[serde(default)]
+    pub output: Vec<Row>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<RowError>,
+    pub duration_ms: f64,
+}
+
+impl Entry {
+    pub fn new(
+        module: &str,
+        function: &str,
+        options: &Options,
+        payload: Vec<Row>,
+        outcome: &Result<Outcome>,
+        duration: Duration,
+    ) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+
+        let (output, errors) = match outcome {
+            Ok(outcome) => (outcome.data.clone(), outcome.errors.clone()),
+            Err(err) => match err.downcast_ref::<batch::RowFailed>() {
+                Some(failed) => (
+                    vec![],
+                    vec![RowError::new(failed.row.clone(), &failed.error)],
+                ),
+                None => (vec![], vec![RowError::new(Value::Null, err)]),
+            },
+        };
+
+        Self {
+            timestamp_ms,
+            module: module.into(),
+            function: function.into(),
+            options: options.clone(),
+            payload,
+            output,
+            errors,
+            duration_ms: duration.as_secs_f64() * 1000.0,
+        }
+    }
+}
+
+/// Appends every request the server handles to a JSONL file.
+pub struct Recorder {
+    file: Mutex<File>,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // a single write keeps concurrent entries on separate lines
+        self.file.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+}
+
+/// What a single input row produced.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RowResult {
+    /// The result rows, without their row ids.
+    Rows(Vec<Row>),
+    Error(String),
+}
+
+impl fmt::Display for RowResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RowResult::Rows(rows) => write!(f, "{}", Value::from(rows.clone())),
+            RowResult::Error(message) => write!(f, "error: {}", message),
+        }
+    }
+}
+
+/// An input row whose result changed between the recording and the replay.
+#[derive(Debug, PartialEq)]
+pub struct Difference {
+    pub row: Value,
+    pub recorded: RowResult,
+    pub replayed: RowResult,
+}
+
+fn row_result(entry: &Entry, row_id: &Value) -> RowResult {
+    if let Some(err) = entry.errors.iter().find(|e| e.row == *row_id) {
+        return RowResult::Error(err.message.clone());
+    }
+    RowResult::Rows(
+        entry
+            .output
+            .iter()
+            .filter(|row| row.first() == Some(row_id))
+            .map(|row| row[1..].to_vec())
+            .collect(),
+    )
+}
+
+/// Compare the results of every input row of two runs of the same request.
+pub fn diff(recorded: &Entry, replayed: &Entry) -> Vec<Difference> {
+    let null = Value::Null;
+    let mut row_ids: Vec<&Value> = recorded.payload.iter().filter_map(|r| r.first()).collect();
+    // errors that aren't tied to a row are recorded against null
+    row_ids.push(&null);
+
+    row_ids
+        .into_iter()
+        .filter_map(|row_id| {
+            let recorded = row_result(recorded, row_id);
+            let replayed = row_result(replayed, row_id);
+            (recorded != replayed).then(|| Difference {
+                row: row_id.clone(),
+                recorded,
+                replayed,
+            })
+        })
+        .collect()
+}
+
+/// Run every request in a recording again and report the rows whose results
+/// differ. Fails if any do.
+pub fn replay(path: impl AsRef<Path>, registry: &Registry) -> Result<()> {
+    let path = path.as_ref();
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+
+    let mut requests = 0;
+    let mut rows = 0;
+    let mut differing = 0;
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let at = format!("{}:{}", path.display(), i + 1);
+
+        let recorded: Entry = serde_json::from_str(&line).with_context(|| at.clone())?;
+        // a recording of a single module can be replayed against it under
+        // any name
+        let module = registry
+            .get(&recorded.module)
+            .or_else(|| registry.only())
+            .ok_or_else(|| anyhow!("{}: unknown module {}", at, recorded.module))?;
+        let signature = module
+            .signature(&recorded.function)
+            .with_context(|| at.clone())?;
+
+        let started = Instant::now();
+        let outcome = batch::run(
+            &module,
+            "replay",
+            &recorded.function,
+            signature,
+            recorded.payload.clone(),
+            &recorded.options,
+        );
+        let replayed = Entry::new(
+            &recorded.module,
+            &recorded.function,
+            &recorded.options,
+            recorded.payload.clone(),
+            &outcome,
+            started.elapsed(),
+        );
+
+        for difference in diff(&recorded, &replayed) {
+            println!(
+                "{} ({}/{}) row {}:\n  recorded: {}\n  replayed: {}",
+                at,
+                recorded.module,
+                recorded.function,
+                difference.row,
+                difference.recorded,
+                difference.replayed
+            );
+            differing += 1;
+        }
+        requests += 1;
+        rows += recorded.payload.len();
+    }
+
+    println!(
+        "replayed {} requests, {} of {} rows differ",
+        requests, differing, rows
+    );
+    if differing > 0 {
+        return Err(anyhow!("{} rows differ from the recording", differing));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn entry(output: Vec<Row>, errors: Vec<RowError>) -> Entry {
+        Entry {
+            timestamp_ms: 0,
+            module: "m".into(),
+            function: "f".into(),
+            options: Options::default(),
+            payload: vec![vec![json!(1), json!("a")], vec![json!(2), json!("b")]],
+            output,
+            errors,
+            duration_ms: 0.0,
+        }
+    }
+
+    fn error(row: Value, message: &str) -> RowError {
+        RowError {
+            row,
+            message: message.into(),
+            backtrace: vec![],
+            panic: None,
+            output: None,
+        }
+    }
+
+    #[test]
+    fn test_same_results() {
+        let recorded = entry(
+            vec![vec![json!(1), json!("A")], vec![json!(2), json!("B")]],
+            vec![],
+        );
+        assert_eq!(diff(&recorded, &recorded.clone()), vec![]);
+    }
+
+    #[test]
+    fn test_changed_rows() {
+        let recorded = entry(
+            vec![vec![json!(1), json!("A")], vec![json!(2), json!("B")]],
+            vec![],
+        );
+        let replayed = entry(
+            vec![vec![json!(1), json!("A")]],
+            vec![error(json!(2), "boom")],
+        );
+        assert_eq!(
+            diff(&recorded, &replayed),
+            vec![Difference {
+                row: json!(2),
+                recorded: RowResult::Rows(vec![vec![json!("B")]]),
+                replayed: RowResult::Error("boom".into()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_request_errors() {
+        let recorded = entry(vec![], vec![error(Value::Null, "Empty row")]);
+        let replayed = entry(vec![], vec![]);
+        assert_eq!(diff(&recorded, &replayed).len(), 1);
+    }
+}
@@ -0,0 +1,393 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::batch::Slots;
+use crate::handle::{FactoryOptions, HandleFactory, HandlerPool, Init, Lifetime};
+use crate::signature::{handler_name, FunctionKind, Signature, Signatures};
+use crate::stats::Stats;
+
+/// A wasm module to serve, as given on the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleSpec {
+    pub name: String,
+    pub wasm_path: PathBuf,
+    pub wit_path: Option<PathBuf>,
+}
+
+impl ModuleSpec {
+    /// Parse the module arguments. Each one is either
+    ///
+    /// - `[name=]path/to/foo.wasm`, optionally followed by `path/to/foo.wit`
+    /// - a directory, in which every `*.wasm` file is served along with the
+    ///   `*.wit` file of the same name if there is one
+    ///
+    /// Modules are named after their file unless a name is given.
+    pub fn parse_args(args: &[String]) -> Result<Vec<ModuleSpec>> {
+        let mut specs: Vec<ModuleSpec> = Vec::new();
+
+        for arg in args {
+            let (name, path) = match arg.split_once('=') {
+                Some((name, path)) => (Some(name.to_string()), Path::new(path)),
+                None => (None, Path::new(arg)),
+            };
+
+            if path.extension().map_or(false, |ext| ext == "wit") {
+                match specs.last_mut() {
+                    Some(spec) if name.is_none() && spec.wit_path.is_none() => {
+                        spec.wit_path = Some(path.to_path_buf())
+                    }
+                    _ => return Err(anyhow!("{} doesn't follow a wasm module", arg)),
+                }
+            } else if path.is_dir() {
+                if name.is_some() {
+                    return Err(anyhow!("{}: directories can't be named", arg));
+                }
+                specs.extend(Self::scan(path)?);
+            } else {
+                let name = match name {
+                    Some(name) => name,
+                    None => module_name(path)?,
+                };
+                specs.push(ModuleSpec {
+                    name,
+                    wasm_path: path.to_path_buf(),
+                    wit_path: None,
+                });
+            }
+        }
+
+        Ok(specs)
+    }
+
+    /// Attach a WIT file given as `[name=]path/to/foo.wit` to its module.
+    /// The name can be left out when there's only one module.
+    pub fn attach_wit(specs: &mut [ModuleSpec], arg: &str) -> Result<()> {
+        let (name, path) = match arg.split_once('=') {
+            Some((name, path)) => (Some(name), path),
+            None => (None, arg),
+        };
+
+        let spec = match name {
+            Some(name) => specs.iter_mut().find(|spec| spec.name == name),
+            None if specs.len() == 1 => specs.first_mut(),
+            None => {
+                return Err(anyhow!(
+                    "{}: name the module the WIT file belongs to, as name={}",
+                    arg,
+                    arg
+                ))
+            }
+        };
+        match spec {
+            Some(spec) => {
+                spec.wit_path = Some(path.into());
+                Ok(())
+            }
+            None => Err(anyhow!("{}: unknown module", arg)),
+        }
+    }
+
+    fn scan(dir: &Path) -> Result<Vec<ModuleSpec>> {
+        let mut specs = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "wasm") {
+                continue;
+            }
+            let wit_path = path.with_extension("wit");
+            specs.push(ModuleSpec {
+                name: module_name(&path)?,
+                wit_path: wit_path.exists().then(|| wit_path),
+                wasm_path: path,
+            });
+        }
+        specs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(specs)
+    }
+}
+
+fn module_name(path: &Path) -> Result<String> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(String::from)
+        .ok_or_else(|| anyhow!("can't name a module after {}", path.display()))
+}
+
+/// A module being served: its instance pool and, when a WIT file was given,
+/// the signatures of its functions.
+pub struct Module {
+    pub name: String,
+    pub pool: Arc<HandlerPool>,
+    pub signatures: Option<Arc<Signatures>>,
+    pub stats: Stats,
+    /// How many parts each batch is split into, computed in parallel.
+    pub parallel: usize,
+    /// Shared by every module, bounding how many instances compute at once.
+    pub slots: Arc<Slots>,
+    kinds: HashMap<String, FunctionKind>,
+}
+
+impl Module {
+    /// The WIT signature of a function. Without a WIT file nothing is known
+    /// about any function, but when there is one, calling a function it
+    /// doesn't declare is an error.
+    pub fn signature(&self, function: &str) -> Result<Option<&Signature>> {
+        match &self.signatures {
+            Some(signatures) => match signatures.get(function) {
+                Some(sig) => Ok(Some(sig)),
+                None => Err(anyhow!(
+                    "function {} is not declared in the WIT file",
+                    function
+                )),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Whether a function is a UDF or a TVF, either as configured for this
+    /// function or as implied by its WIT signature.
+    pub fn kind(&self, function: &str) -> Option<FunctionKind> {
+        let function = handler_name(function);
+        self.kinds
+            .get(&format!("{}/{}", self.name, function))
+            .or_else(|| self.kinds.get(&function))
+            .copied()
+            .or_else(|| {
+                let sig = self.signatures.as_ref()?.get(&function)?;
+                Some(sig.kind())
+            })
+    }
+}
+
+/// How every module is loaded and served.
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub lifetime: Lifetime,
+    pub factory: FactoryOptions,
+    /// Overrides the kind of functions, keyed by `function` or
+    /// `module/function`.
+    pub kinds: HashMap<String, FunctionKind>,
+    /// Steps initializing the instances of the module named, or of every
+    /// module.
+    pub inits: Vec<(Option<String>, Init)>,
+    /// Options of the named module overriding the shared ones, as
+    /// `(module, option, value)`.
+    pub overrides: Vec<(String, String, String)>,
+    pub parallel: usize,
+    /// The most instances computing rows at once, across every module.
+    pub max_concurrency: usize,
+}
+
+/// Every module served by the debugger, by name.
+#[derive(Default)]
+pub struct Registry {
+    modules: BTreeMap<String, Arc<Module>>,
+}
+
+impl Registry {
+    /// Compile every module. Each one gets its own engine, so limits and
+    /// other engine configuration don't leak between modules.
+    pub fn load(specs: Vec<ModuleSpec>, config: &Config) -> Result<Self> {
+        if specs.is_empty() {
+            return Err(anyhow!("no wasm modules to serve"));
+        }
+        let named = config
+            .inits
+            .iter()
+            .filter_map(|(module, _)| Some(("--init", module.as_ref()?)))
+            .chain(
+                config
+                    .overrides
+                    .iter()
+                    .map(|(module, _, _)| ("--module-option", module)),
+            );
+        for (flag, name) in named {
+            if !specs.iter().any(|spec| spec.name == *name) {
+                return Err(anyhow!(
+                    "{} names module {}, which isn't served",
+                    flag,
+                    name
+                ));
+            }
+        }
+
+        let mut registry = Registry::default();
+        let slots = Arc::new(Slots::new(config.max_concurrency));
+        for spec in specs {
+            println!("debugging {}: {}", spec.name, spec.wasm_path.display());
+            let signatures = match &spec.wit_path {
+                Some(wit_path) => {
+                    println!("  with wit: {}", wit_path.display());
+                    Some(Arc::new(Signatures::from_file(wit_path)?))
+                }
+                None => None,
+            };
+
+            let mut options = config.factory.clone();
+            let overrides = config
+                .overrides
+                .iter()
+                .filter(|(name, _, _)| *name == spec.name);
+            for (_, option, value) in overrides {
+                options.set(option, value).with_context(|| {
+                    format!("--module-option {}/{}={}", spec.name, option, value)
+                })?;
+            }
+            options.init = config
+                .inits
+                .iter()
+                .filter(|(module, _)| module.as_ref().map_or(true, |name| *name == spec.name))
+                .map(|(_, init)| init.clone())
+                .collect();
+            let factory = HandleFactory::new(&spec.wasm_path, options)?;
+            if let Some(size) = factory.snapshot_size() {
+                println!("  instances start from a snapshot of {} bytes", size);
+            }
+            let module = Module {
+                name: spec.name.clone(),
+                pool: Arc::new(HandlerPool::new(factory, config.lifetime)),
+                signatures,
+                stats: Stats::default(),
+                parallel: config.parallel,
+                slots: slots.clone(),
+                kinds: config
+                    .kinds
+                    .iter()
+                    .map(|(name, kind)| (kind_key(name), *kind))
+                    .collect(),
+            };
+            if registry
+                .modules
+                .insert(spec.name.clone(), Arc::new(module))
+                .is_some()
+            {
+                return Err(anyhow!("module {} was given more than once", spec.name));
+            }
+        }
+        Ok(registry)
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Module>> {
+        self.modules.get(name).cloned()
+    }
+
+    /// The module to use when a request doesn't name one, which is only
+    /// unambiguous when a single module is being served.
+    pub fn only(&self) -> Option<Arc<Module>> {
+        match self.modules.len() {
+            1 => self.modules.values().next().cloned(),
+            _ => None,
+        }
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = &Arc<Module>> {
+        self.modules.values()
+    }
+}
+
+/// The key a `[module/]function` kind is looked up by, with the function
+/// named as its handler is but the module name left as given.
+fn kind_key(name: &str) -> String {
+    match name.split_once('/') {
+        Some((module, function)) => format!("{}/{}", module, handler_name(function)),
+        None => handler_name(name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let specs =
+            ModuleSpec::parse_args(&args(&["a/power.wasm", "b/power.wit", "re=a/s2regex.wasm"]))
+                .unwrap();
+        assert_eq!(
+            specs,
+            vec![
+                ModuleSpec {
+                    name: "power".into(),
+                    wasm_path: "a/power.wasm".into(),
+                    wit_path: Some("b/power.wit".into()),
+                },
+                ModuleSpec {
+                    name: "re".into(),
+                    wasm_path: "a/s2regex.wasm".into(),
+                    wit_path: None,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_attach_wit() {
+        let mut specs = ModuleSpec::parse_args(&args(&["a/power.wasm"])).unwrap();
+        ModuleSpec::attach_wit(&mut specs, "b/power.wit").unwrap();
+        assert_eq!(specs[0].wit_path, Some("b/power.wit".into()));
+
+        let mut specs = ModuleSpec::parse_args(&args(&["a.wasm", "b.wasm"])).unwrap();
+        assert!(ModuleSpec::attach_wit(&mut specs, "b.wit").is_err());
+        assert!(ModuleSpec::attach_wit(&mut specs, "c=c.wit").is_err());
+        ModuleSpec::attach_wit(&mut specs, "b=b.wit").unwrap();
+        assert_eq!(specs[1].wit_path, Some("b.wit".into()));
+    }
+
+    #[test]
+    fn test_wit_needs_module() {
+        assert!(ModuleSpec::parse_args(&args(&["power.wit"])).is_err());
+        assert!(ModuleSpec::parse_args(&args(&["a.wasm", "a.wit", "b.wit"])).is_err());
+    }
+
+    #[test]
+    fn test_init_needs_served_module() {
+        let config = Config {
+            lifetime: Lifetime::Request,
+            factory: FactoryOptions::default(),
+            kinds: HashMap::new(),
+            inits: vec![(Some("other".into()), Init::Export("init".into()))],
+            overrides: vec![],
+            parallel: 1,
+            max_concurrency: 1,
+        };
+        let specs = ModuleSpec::parse_args(&args(&["power.wasm"])).unwrap();
+        let err = Registry::load(specs, &config).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "--init names module other, which isn't served"
+        );
+    }
+
+    #[test]
+    fn test_override_needs_served_module() {
+        let config = Config {
+            lifetime: Lifetime::Request,
+            factory: FactoryOptions::default(),
+            kinds: HashMap::new(),
+            inits: vec![],
+            overrides: vec![("other".into(), "fuel".into(), "1000".into())],
+            parallel: 1,
+            max_concurrency: 1,
+        };
+        let specs = ModuleSpec::parse_args(&args(&["power.wasm"])).unwrap();
+        let err = Registry::load(specs, &config).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "--module-option names module other, which isn't served"
+        );
+    }
+
+    #[test]
+    fn test_kind_key() {
+        assert_eq!(kind_key("s2-regex/match-all"), "s2-regex/match_all");
+        assert_eq!(kind_key("match-all"), "match_all");
+    }
+}
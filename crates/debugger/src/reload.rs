@@ -0,0 +1,44 @@
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use async_std::task;
+use tide::log;
+
+use crate::handle::HandlerPool;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Watch the module the pool's factory was built from and swap in a
+/// recompiled factory whenever the file changes. Requests already running
+/// finish on the instances they checked out.
+pub fn watch(pool: Arc<HandlerPool>) {
+    let path = pool.factory().wasm_path().to_path_buf();
+
+    task::spawn(async move {
+        let mut last_modified = modified(&path);
+        loop {
+            task::sleep(POLL_INTERVAL).await;
+
+            let current = modified(&path);
+            if current.is_none() || current == last_modified {
+                // a missing file is usually just cargo in the middle of a build
+                continue;
+            }
+            last_modified = current;
+
+            let current = pool.factory();
+            match task::spawn_blocking(move || current.reload()).await {
+                Ok(reloaded) => {
+                    pool.replace_factory(reloaded);
+                    log::info!("reloaded {}", path.display());
+                }
+                Err(err) => log::error!("failed to reload {}: {:?}", path.display(), err),
+            }
+        }
+    });
+}
+
+fn modified(path: &std::path::Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
@@ -0,0 +1,356 @@
+use anyhow::{anyhow, Result};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde_json::Value;
+use std::time::Instant;
+
+use crate::cli::ReplArgs;
+use crate::coerce;
+use crate::handle::{FactoryOptions, GuestError, HandleFactory, Handler, HandlerInfo};
+use crate::signature::{handler_name, Signatures};
+
+const COMMANDS: &[(&str, &str)] = &[
+    (":functions", "list the functions the module exports"),
+    (":reset", "start over with a fresh instance"),
+    (":memory", "show how much memory the instance uses"),
+    (
+        ":capture",
+        "toggle showing the guest's output apart from results",
+    ),
+    (":help", "show this help"),
+    (":quit", "exit"),
+];
+
+/// Completes function and command names at the start of the line.
+struct ReplHelper {
+    names: Vec<String>,
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let prefix = &line[..pos];
+        if prefix.contains(|c: char| c == '(' || c.is_whitespace()) {
+            return Ok((pos, vec![]));
+        }
+        let candidates = self
+            .names
+            .iter()
+            .filter(|name| name.starts_with(prefix))
+            .cloned()
+            .collect();
+        Ok((0, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+struct Repl {
+    factory: HandleFactory,
+    signatures: Option<Signatures>,
+    handler: Handler,
+    catalog: Vec<HandlerInfo>,
+    /// Show the guest's output in its own section after each result, rather
+    /// than passing it straight through.
+    capture: bool,
+}
+
+/// Explore a module interactively, calling its functions on a single
+/// instance that lives until it's reset.
+pub fn run(args: &ReplArgs) -> Result<()> {
+    let factory = HandleFactory::new(
+        &args.wasm,
+        FactoryOptions {
+            limits: args.limits.limits(),
+            wasi: args.wasi.options()?,
+            ..FactoryOptions::default()
+        },
+    )?;
+    let signatures = match &args.wit {
+        Some(wit_path) => Some(Signatures::from_file(wit_path)?),
+        None => None,
+    };
+    let mut handler = factory.make_handler()?;
+    let catalog = handler.list_handlers(signatures.as_ref())?;
+
+    let mut repl = Repl {
+        factory,
+        signatures,
+        handler,
+        catalog,
+        capture: true,
+    };
+    repl.handler.set_echo(!repl.capture);
+
+    let mut editor = Editor::<ReplHelper>::new();
+    editor.set_helper(Some(ReplHelper {
+        names: COMMANDS
+            .iter()
+            .map(|(command, _)| command.to_string())
+            .chain(repl.catalog.iter().map(|h| h.name.clone()))
+            .collect(),
+    }));
+
+    println!(
+        "{} functions in {}, type :help for help",
+        repl.catalog.len(),
+        args.wasm.display()
+    );
+
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        editor.add_history_entry(line);
+
+        let res = match line {
+            ":quit" | ":q" => break,
+            ":help" => {
+                println!("call a function with name(arg, ...), e.g. power_of(2, 10)");
+                println!("arguments are JSON or SQL literals like 'text', NULL and TRUE");
+                for (command, help) in COMMANDS {
+                    println!("  {:<12} {}", command, help);
+                }
+                Ok(())
+            }
+            ":functions" => {
+                repl.list_functions();
+                Ok(())
+            }
+            ":reset" => repl.reset().map(|_| println!("started a fresh instance")),
+            ":memory" => {
+                for (name, size) in repl.handler.memory_usage() {
+                    println!("{}: {} bytes ({} pages)", name, size, size / 65536);
+                }
+                Ok(())
+            }
+            ":capture" => {
+                repl.capture = !repl.capture;
+                repl.handler.set_echo(!repl.capture);
+                println!(
+                    "guest output is {}",
+                    if repl.capture {
+                        "captured"
+                    } else {
+                        "passed through"
+                    }
+                );
+                Ok(())
+            }
+            _ if line.starts_with(':') => Err(anyhow!("unknown command {}, try :help", line)),
+            _ => repl.call(line),
+        };
+        if let Err(err) = res {
+            println!("error: {}", err);
+        }
+    }
+
+    Ok(())
+}
+
+impl Repl {
+    fn reset(&mut self) -> Result<()> {
+        self.handler = self.factory.make_handler()?;
+        self.handler.set_echo(!self.capture);
+        Ok(())
+    }
+
+    fn list_functions(&self) {
+        for handler in &self.catalog {
+            let params: Vec<_> = handler
+                .params
+                .iter()
+                .map(|p| format!("{}: {}", p.name, p.ty))
+                .collect();
+            println!(
+                "{}({}) -> {}",
+                handler.name,
+                params.join(", "),
+                handler.result
+            );
+        }
+    }
+
+    fn call(&mut self, line: &str) -> Result<()> {
+        let (name, mut args) = parse_call(line)?;
+        self.handler.clear_captured();
+        if let Some(signatures) = &self.signatures {
+            let sig = signatures
+                .get(&name)
+                .ok_or_else(|| anyhow!("function {} is not declared in the WIT file", name))?;
+            args = coerce::coerce_args(sig, args)?;
+        }
+
+        let started = Instant::now();
+        let res = self
+            .handler
+            .handle_json(handler_name(&name), serde_json::to_vec(&args)?);
+        let elapsed = started.elapsed();
+
+        if self.capture {
+            let output = self.handler.captured();
+            if !output.stdout.is_empty() {
+                println!("--- stdout ---\n{}", output.stdout.trim_end());
+            }
+            if !output.stderr.is_empty() {
+                println!("--- stderr ---\n{}", output.stderr.trim_end());
+            }
+        }
+
+        match res {
+            Ok(output) => {
+                let result: Value = serde_json::from_slice(&output)?;
+                println!("{}", serde_json::to_string_pretty(&result)?);
+                println!("({:?})", elapsed);
+                Ok(())
+            }
+            Err(err) => {
+                if let Some(guest) = err.downcast_ref::<GuestError>() {
+                    for frame in &guest.backtrace {
+                        println!("  {}", frame);
+                    }
+                }
+                // the trapped instance can't be trusted with another call
+                if self.handler.trapped() {
+                    self.reset()?;
+                    println!("the instance trapped, so a fresh one was started");
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Parse a call such as `power_of(2, 10)` or `power_of 2, 10`.
+fn parse_call(line: &str) -> Result<(String, Vec<Value>)> {
+    let line = line.trim();
+    let name_end = line
+        .find(|c: char| c == '(' || c.is_whitespace())
+        .unwrap_or(line.len());
+    let (name, rest) = line.split_at(name_end);
+    let rest = rest.trim();
+    let args = match rest.strip_prefix('(') {
+        Some(inner) => inner
+            .strip_suffix(')')
+            .ok_or_else(|| anyhow!("missing ) after the arguments"))?,
+        None => rest,
+    };
+    Ok((name.to_string(), parse_args(args)?))
+}
+
+/// Parse comma separated arguments, each either JSON or a SQL literal.
+fn parse_args(mut s: &str) -> Result<Vec<Value>> {
+    let mut args = Vec::new();
+    loop {
+        s = s.trim_start();
+        if s.is_empty() {
+            return Ok(args);
+        }
+        let (value, rest) = parse_literal(s)?;
+        args.push(value);
+        s = rest.trim_start();
+        if !s.is_empty() {
+            s = s
+                .strip_prefix(',')
+                .ok_or_else(|| anyhow!("expected , before {}", s))?;
+        }
+    }
+}
+
+fn parse_literal(s: &str) -> Result<(Value, &str)> {
+    // SQL strings are single quoted, with '' standing for a quote
+    if let Some(rest) = s.strip_prefix('\'') {
+        let mut text = String::new();
+        let mut chars = rest.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            if c != '\'' {
+                text.push(c);
+            } else if chars.peek().map(|(_, c)| *c) == Some('\'') {
+                text.push('\'');
+                chars.next();
+            } else {
+                return Ok((Value::String(text), &rest[i + 1..]));
+            }
+        }
+        return Err(anyhow!("unterminated string '{}", rest));
+    }
+
+    let word_end = s
+        .find(|c: char| c == ',' || c.is_whitespace())
+        .unwrap_or(s.len());
+    let keyword = match s[..word_end].to_ascii_uppercase().as_str() {
+        "NULL" => Some(Value::Null),
+        "TRUE" => Some(Value::Bool(true)),
+        "FALSE" => Some(Value::Bool(false)),
+        _ => None,
+    };
+    if let Some(value) = keyword {
+        return Ok((value, &s[word_end..]));
+    }
+
+    let mut values = serde_json::Deserializer::from_str(s).into_iter::<Value>();
+    match values.next() {
+        Some(Ok(value)) => Ok((value, &s[values.byte_offset()..])),
+        _ => Err(anyhow!("can't parse {} as JSON or a SQL literal", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse_call() {
+        assert_eq!(
+            parse_call("power_of(2, -10)").unwrap(),
+            ("power_of".to_string(), vec![json!(2), json!(-10)])
+        );
+        assert_eq!(
+            parse_call("echo 'it''s', NULL").unwrap(),
+            ("echo".to_string(), vec![json!("it's"), Value::Null])
+        );
+        assert!(parse_call("noop()").unwrap().1.is_empty());
+    }
+
+    #[test]
+    fn test_parse_json_args() {
+        assert_eq!(
+            parse_args(r#"{"a": [1, 2]}, "b",true,[]"#).unwrap(),
+            vec![json!({"a": [1, 2]}), json!("b"), json!(true), json!([])]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_call("f(1").is_err());
+        assert!(parse_args("'open").is_err());
+        assert!(parse_args("1 2").is_err());
+        assert!(parse_args("bare").is_err());
+    }
+}
@@ -0,0 +1,275 @@
+//! SingleStore's ROWDAT_1 binary format for external functions.
+//!
+//! Every row is a little-endian `i64` row id followed by one value per
+//! column. Each value starts with a null byte (`1` when the value is NULL)
+//! followed by the value itself, which is present even when it is NULL:
+//! fixed-width little-endian numbers, or an `i64` length followed by that
+//! many bytes for strings.
+//!
+//! Decoded rows are passed to guests as JSON, which has no room for NaN or
+//! infinite floats, so a request holding one that isn't NULL is rejected.
+
+use anyhow::{anyhow, Result};
+use serde_json::{Number, Value};
+
+use crate::signature::WitType;
+
+pub const CONTENT_TYPE: &str = "application/octet-stream";
+
+/// How a column is laid out on the wire.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Column {
+    Bool,
+    Int8,
+    Int16,
+    Int32,
+    Int64,
+    UInt8,
+    UInt16,
+    UInt32,
+    UInt64,
+    Float32,
+    Float64,
+    String,
+}
+
+impl Column {
+    fn of(ty: &WitType) -> Column {
+        match ty {
+            WitType::Bool => Column::Bool,
+            WitType::S8 => Column::Int8,
+            WitType::S16 => Column::Int16,
+            WitType::S32 => Column::Int32,
+            WitType::S64 => Column::Int64,
+            WitType::U8 => Column::UInt8,
+            WitType::U16 => Column::UInt16,
+            WitType::U32 => Column::UInt32,
+            WitType::U64 => Column::UInt64,
+            WitType::Float32 => Column::Float32,
+            WitType::Float64 => Column::Float64,
+            WitType::Option(inner) => Column::of(inner),
+            // everything else travels as text, JSON encoded when structured
+            _ => Column::String,
+        }
+    }
+}
+
+/// Decode a ROWDAT_1 request into rows of the form `[id, args...]`.
+pub fn decode(mut data: &[u8], types: &[WitType]) -> Result<Vec<Vec<Value>>> {
+    let columns: Vec<Column> = types.iter().map(Column::of).collect();
+
+    let mut rows = Vec::new();
+    while !data.is_empty() {
+        let mut row = Vec::with_capacity(columns.len() + 1);
+        row.push(Value::from(read_i64(&mut data)?));
+
+        for column in &columns {
+            let is_null = take(&mut data, 1)?[0] != 0;
+            row.push(read_value(&mut data, *column, is_null)?);
+        }
+
+        rows.push(row);
+    }
+
+    Ok(rows)
+}
+
+/// Encode result rows of the form `[id, values...]` as ROWDAT_1.
+pub fn encode(rows: &[Vec<Value>], types: &[WitType]) -> Result<Vec<u8>> {
+    let columns: Vec<Column> = types.iter().map(Column::of).collect();
+
+    let mut out = Vec::new();
+    for row in rows {
+        if row.len() != columns.len() + 1 {
+            return Err(anyhow!(
+                "expected {} result columns, found {}",
+                columns.len(),
+                row.len().saturating_sub(1)
+            ));
+        }
+
+        let id = row[0]
+            .as_i64()
+            .ok_or_else(|| anyhow!("row id {} isn't an integer", row[0]))?;
+        out.extend_from_slice(&id.to_le_bytes());
+
+        for (column, value) in columns.iter().zip(&row[1..]) {
+            out.push(value.is_null() as u8);
+            write_value(&mut out, *column, value)?;
+        }
+    }
+
+    Ok(out)
+}
+
+fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+    if data.len() < n {
+        return Err(anyhow!("truncated ROWDAT_1 payload"));
+    }
+    let (head, rest) = data.split_at(n);
+    *data = rest;
+    Ok(head)
+}
+
+fn read_i64(data: &mut &[u8]) -> Result<i64> {
+    Ok(i64::from_le_bytes(take(data, 8)?.try_into()?))
+}
+
+/// Read a value, which is `Value::Null` when `is_null` whatever its bytes.
+fn read_value(data: &mut &[u8], column: Column, is_null: bool) -> Result<Value> {
+    let float = |f: f64| match Number::from_f64(f) {
+        _ if is_null => Ok(Value::Null),
+        Some(n) => Ok(Value::Number(n)),
+        None => Err(anyhow!("{} can't be passed to the guest as JSON", f)),
+    };
+    let value = match column {
+        Column::Bool => Value::Bool(take(data, 1)?[0] != 0),
+        Column::Int8 => Value::from(i8::from_le_bytes(take(data, 1)?.try_into()?)),
+        Column::Int16 => Value::from(i16::from_le_bytes(take(data, 2)?.try_into()?)),
+        Column::Int32 => Value::from(i32::from_le_bytes(take(data, 4)?.try_into()?)),
+        Column::Int64 => Value::from(read_i64(data)?),
+        Column::UInt8 => Value::from(u8::from_le_bytes(take(data, 1)?.try_into()?)),
+        Column::UInt16 => Value::from(u16::from_le_bytes(take(data, 2)?.try_into()?)),
+        Column::UInt32 => Value::from(u32::from_le_bytes(take(data, 4)?.try_into()?)),
+        Column::UInt64 => Value::from(u64::from_le_bytes(take(data, 8)?.try_into()?)),
+        Column::Float32 => float(f32::from_le_bytes(take(data, 4)?.try_into()?).into())?,
+        Column::Float64 => float(f64::from_le_bytes(take(data, 8)?.try_into()?))?,
+        Column::String => {
+            let len = read_i64(data)?;
+            let len = usize::try_from(len).map_err(|_| anyhow!("negative string length"))?;
+            Value::String(String::from_utf8(take(data, len)?.to_vec())?)
+        }
+    };
+    Ok(if is_null { Value::Null } else { value })
+}
+
+fn write_value(out: &mut Vec<u8>, column: Column, value: &Value) -> Result<()> {
+    let int = || -> Result<i64> {
+        match value {
+            Value::Null => Ok(0),
+            Value::Bool(b) => Ok(*b as i64),
+            v => v
+                .as_i64()
+                .ok_or_else(|| anyhow!("expected an integer, found {}", v)),
+        }
+    };
+    let uint = || -> Result<u64> {
+        match value {
+            Value::Null => Ok(0),
+            Value::Bool(b) => Ok(*b as u64),
+            v => v
+                .as_u64()
+                .ok_or_else(|| anyhow!("expected an unsigned integer, found {}", v)),
+        }
+    };
+    let float = || -> Result<f64> {
+        match value {
+            Value::Null => Ok(0.0),
+            v => v
+                .as_f64()
+                .ok_or_else(|| anyhow!("expected a number, found {}", v)),
+        }
+    };
+
+    match column {
+        Column::Bool | Column::Int8 => out.extend_from_slice(&i8::try_from(int()?)?.to_le_bytes()),
+        Column::Int16 => out.extend_from_slice(&i16::try_from(int()?)?.to_le_bytes()),
+        Column::Int32 => out.extend_from_slice(&i32::try_from(int()?)?.to_le_bytes()),
+        Column::Int64 => out.extend_from_slice(&int()?.to_le_bytes()),
+        Column::UInt8 => out.extend_from_slice(&u8::try_from(uint()?)?.to_le_bytes()),
+        Column::UInt16 => out.extend_from_slice(&u16::try_from(uint()?)?.to_le_bytes()),
+        Column::UInt32 => out.extend_from_slice(&u32::try_from(uint()?)?.to_le_bytes()),
+        Column::UInt64 => out.extend_from_slice(&uint()?.to_le_bytes()),
+        Column::Float32 => out.extend_from_slice(&(float()? as f32).to_le_bytes()),
+        Column::Float64 => out.extend_from_slice(&float()?.to_le_bytes()),
+        Column::String => {
+            let text = match value {
+                Value::Null => String::new(),
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            };
+            out.extend_from_slice(&(text.len() as i64).to_le_bytes());
+            out.extend_from_slice(text.as_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_roundtrip() {
+        let types = vec![
+            WitType::S32,
+            WitType::Float64,
+            WitType::String,
+            WitType::Option(Box::new(WitType::U64)),
+            WitType::Bool,
+        ];
+        let rows = vec![
+            vec![
+                json!(1),
+                json!(-7),
+                json!(0.1),
+                json!("héllo"),
+                json!(u64::MAX),
+                json!(true),
+            ],
+            vec![
+                json!(2),
+                json!(0),
+                json!(2.5),
+                json!(""),
+                Value::Null,
+                json!(false),
+            ],
+        ];
+
+        let encoded = encode(&rows, &types).unwrap();
+        assert_eq!(decode(&encoded, &types).unwrap(), rows);
+    }
+
+    #[test]
+    fn test_layout() {
+        let encoded = encode(&[vec![json!(3), json!("ab")]], &[WitType::String]).unwrap();
+        assert_eq!(
+            encoded,
+            [
+                &3i64.to_le_bytes()[..],
+                &[0],
+                &2i64.to_le_bytes()[..],
+                &b"ab"[..]
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn test_optional_bool() {
+        let types = vec![WitType::Option(Box::new(WitType::Bool))];
+        let rows = vec![vec![json!(1), json!(true)], vec![json!(2), Value::Null]];
+        let encoded = encode(&rows, &types).unwrap();
+        assert_eq!(decode(&encoded, &types).unwrap(), rows);
+    }
+
+    #[test]
+    fn test_non_finite_floats() {
+        let row = |null: u8, f: f64| [&1i64.to_le_bytes()[..], &[null], &f.to_le_bytes()].concat();
+        assert!(decode(&row(0, f64::NAN), &[WitType::Float64]).is_err());
+        assert!(decode(&row(0, f64::INFINITY), &[WitType::Float64]).is_err());
+        assert_eq!(
+            decode(&row(1, f64::NAN), &[WitType::Float64]).unwrap(),
+            vec![vec![json!(1), Value::Null]]
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let encoded = encode(&[vec![json!(3), json!(7)]], &[WitType::S64]).unwrap();
+        assert!(decode(&encoded[..encoded.len() - 1], &[WitType::S64]).is_err());
+    }
+}
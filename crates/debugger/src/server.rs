@@ -1,68 +1,375 @@
-use anyhow::{anyhow, Result};
-use serde::{Deserialize, Serialize};
-use serde_json::{self, Value};
-use tide::{Body, Request, Response};
+use anyhow::Result;
+use async_std::task;
+use serde::Serialize;
+use serde_json;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use tide::{Body, Request, Response, StatusCode};
 
+use crate::attach::GuestThread;
+use crate::batch::{self, Payload, Row};
+use crate::coerce;
 use crate::handle;
+use crate::metrics;
+use crate::record::{self, Recorder};
+use crate::registry::Registry;
+use crate::reload;
+use crate::rowdat;
+use crate::signature::{FunctionKind, Signature, WitType};
+use crate::sql;
 
 #[derive(Clone)]
 struct State {
-    factory: handle::HandleFactory,
+    registry: Arc<Registry>,
+    recorder: Option<Arc<Recorder>>,
+    guest_thread: Option<Arc<GuestThread>>,
 }
 
-pub async fn listen_and_serve(port: u16, factory: handle::HandleFactory) -> Result<()> {
-    tide::log::start();
+impl State {
+    /// Run guest code on a thread of its own, since it blocks, or on the
+    /// guest thread when there is one.
+    async fn run_guest<R, F>(&self, job: F) -> R
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        match self.guest_thread.clone() {
+            Some(thread) => task::spawn_blocking(move || thread.run(job)).await,
+            None => task::spawn_blocking(job).await,
+        }
+    }
+}
 
-    let state = State { factory };
+pub async fn listen_and_serve(
+    addr: SocketAddr,
+    registry: Arc<Registry>,
+    recorder: Option<Recorder>,
+    guest_thread: Option<GuestThread>,
+) -> Result<()> {
+    for module in registry.modules() {
+        reload::watch(module.pool.clone());
+    }
+
+    let state = State {
+        registry,
+        recorder: recorder.map(Arc::new),
+        guest_thread: guest_thread.map(Arc::new),
+    };
     let mut app = tide::with_state(state);
     app.with(tide::log::LogMiddleware::new());
 
-    app.at("/:name").post(handle_json);
-    app.listen(format!("0.0.0.0:{}", port)).await?;
+    app.at("/").get(list_functions);
+    app.at("/functions").get(list_functions);
+    app.at("/pool").get(pool_stats);
+    app.at("/stats").get(call_stats);
+    app.at("/metrics").get(metrics);
+    app.at("/healthz").get(healthz);
+    app.at("/sql").get(external_sql);
+    app.at("/sql/drop").get(drop_sql);
+    // functions can be called without naming their module when only one
+    // module is being served
+    app.at("/:function").post(handle_json);
+    app.at("/:module/:function").post(handle_json);
+    app.listen(addr).await?;
 
     Ok(())
 }
 
-type Row = Vec<Value>;
+/// The data format of an external function request, chosen by its content
+/// type. Responses use the same format as the request.
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    RowDat1,
+}
+
+impl Format {
+    fn of(req: &Request<State>) -> Self {
+        match req.content_type() {
+            Some(mime) if mime.essence() == rowdat::CONTENT_TYPE => Format::RowDat1,
+            _ => Format::Json,
+        }
+    }
+}
+
+/// A function in the catalog served at `/functions`: the handler as the
+/// guest describes it, plus what the WIT file declares for it, if anything.
+#[derive(Serialize)]
+struct FunctionInfo {
+    #[serde(flatten)]
+    handler: handle::HandlerInfo,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kind: Option<FunctionKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wit: Option<WitInfo>,
+}
+
+#[derive(Serialize)]
+struct WitInfo {
+    name: String,
+    params: Vec<handle::ParamInfo>,
+    result: String,
+}
 
-#[derive(Serialize, Deserialize)]
-struct Payload {
+impl From<&Signature> for WitInfo {
+    fn from(sig: &Signature) -> Self {
+        Self {
+            name: sig.name.clone(),
+            params: sig
+                .params
+                .iter()
+                .map(|(name, ty)| handle::ParamInfo {
+                    name: name.clone(),
+                    ty: ty.to_string(),
+                })
+                .collect(),
+            result: sig.result.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorPayload {
     data: Vec<Row>,
+    errors: Vec<batch::RowError>,
 }
 
 async fn handle_json(mut req: Request<State>) -> tide::Result {
-    let state = req.state();
-    let mut handler = state.factory.make_handler()?;
+    let module = match req.param("module") {
+        Ok(name) => req.state().registry.get(name),
+        Err(_) => req.state().registry.only(),
+    };
+    let module = match module {
+        Some(module) => module,
+        None => {
+            return Ok(error_response(
+                StatusCode::NotFound,
+                "unknown module, functions are served at /<module>/<function>".into(),
+            ))
+        }
+    };
+    let connection = req.peer_addr().unwrap_or_default().to_string();
+
+    let mut options: batch::Options = req.query()?;
+    options.debug |= req.header("x-debug-output").is_some();
+    if let Some(seed) = req.header("x-debug-seed") {
+        match seed.as_str().parse() {
+            Ok(seed) => options.seed = Some(seed),
+            Err(_) => {
+                return Ok(error_response(
+                    StatusCode::BadRequest,
+                    format!(
+                        "invalid x-debug-seed {:?}, expected an integer",
+                        seed.as_str()
+                    ),
+                ))
+            }
+        }
+    }
+    if options.seed.is_some() && !module.pool.factory().options().wasi.deterministic {
+        return Ok(error_response(
+            StatusCode::BadRequest,
+            "seeding a request needs the debugger to run with --deterministic".into(),
+        ));
+    }
+    let format = Format::of(&req);
+    if format == Format::RowDat1 && options.errors == batch::ErrorMode::Rows {
+        return Ok(error_response(
+            StatusCode::BadRequest,
+            "errors=rows needs a JSON request, ROWDAT_1 responses have no room for errors".into(),
+        ));
+    }
+    let body = req.body_bytes().await?;
+    let name = req.param("function")?;
+
+    let signature = match module.signature(name) {
+        Ok(signature) => signature,
+        Err(err) => return Ok(error_response(StatusCode::NotFound, err.to_string())),
+    };
 
-    let payload: Payload = req.body_json().await?;
-    let name = req.param("name")?;
+    let rows = match (format, signature) {
+        (Format::Json, _) => match serde_json::from_slice::<Payload>(&body) {
+            Ok(payload) => payload.data,
+            Err(err) => return Ok(error_response(StatusCode::BadRequest, err.to_string())),
+        },
+        (Format::RowDat1, Some(sig)) => {
+            let types: Vec<_> = sig.params.iter().map(|(_, ty)| ty.clone()).collect();
+            match rowdat::decode(&body, &types) {
+                Ok(rows) => rows,
+                Err(err) => return Ok(error_response(StatusCode::BadRequest, err.to_string())),
+            }
+        }
+        (Format::RowDat1, None) => {
+            return Ok(error_response(
+                StatusCode::BadRequest,
+                "ROWDAT_1 requests need the module's WIT file to know the column types".into(),
+            ))
+        }
+    };
 
-    let mut result = Vec::new();
-    for row in payload.data {
-        if row.len() == 0 {
-            return Err(anyhow!("Empty row").into());
+    let recording = req.state().recorder.as_ref().map(|_| rows.clone());
+    let started = Instant::now();
+    let job = {
+        let (module, name, options) = (module.clone(), name.to_string(), options.clone());
+        move || {
+            let signature = module.signature(&name)?;
+            batch::run(&module, &connection, &name, signature, rows, &options)
         }
+    };
+    let outcome = req.state().run_guest(job).await;
 
-        let row_id = row[0].clone();
-        let row_input = row[1..].to_vec();
+    if let (Some(recorder), Some(payload)) = (&req.state().recorder, recording) {
+        let entry = record::Entry::new(
+            &module.name,
+            name,
+            &options,
+            payload,
+            &outcome,
+            started.elapsed(),
+        );
+        if let Err(err) = recorder.record(&entry) {
+            tide::log::error!("failed to record request: {}", err);
+        }
+    }
 
-        let output_raw = handler.handle_json(name.into(), serde_json::to_vec(&row_input)?)?;
-        let output: Value = serde_json::from_slice(&output_raw)?;
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            let status = match err.downcast_ref::<batch::RowFailed>() {
+                Some(failed) if failed.error.is::<coerce::ArgError>() => StatusCode::BadRequest,
+                _ => StatusCode::InternalServerError,
+            };
+            return Ok(error_response(status, err.to_string()));
+        }
+    };
 
-        let encode_value = |v: &Value| match v {
-            Value::Array(_) | Value::Object(_) => Value::String(serde_json::to_string(v).unwrap()),
-            _ => v.clone(),
-        };
+    if !outcome.errors.is_empty() {
+        let mut res = Response::new(StatusCode::InternalServerError);
+        res.set_body(Body::from_json(&ErrorPayload {
+            data: outcome.data,
+            errors: outcome.errors,
+        })?);
+        return Ok(res);
+    }
 
-        // if output is an array, then expand into multiple rows
-        if output.is_array() {
-            for row in output.as_array().unwrap() {
-                result.push(vec![row_id.clone(), encode_value(row)]);
-            }
-        } else {
-            result.push(vec![row_id.clone(), encode_value(&output)]);
+    match (format, signature) {
+        (Format::RowDat1, Some(sig)) => {
+            let columns = if options.debug {
+                // the debug envelope is sent as JSON text
+                vec![WitType::String]
+            } else {
+                sig.result_columns(module.kind(name).unwrap_or_else(|| sig.kind()))
+            };
+            let mut res = Response::new(StatusCode::Ok);
+            res.set_body(Body::from_bytes(rowdat::encode(&outcome.data, &columns)?));
+            res.set_content_type(rowdat::CONTENT_TYPE);
+            Ok(res)
         }
+        _ => Ok(Response::from(Body::from_json(&Payload {
+            data: outcome.data,
+        })?)),
     }
+}
+
+async fn list_functions(req: Request<State>) -> tide::Result {
+    let registry = req.state().registry.clone();
+
+    let catalog = req
+        .state()
+        .run_guest(move || -> anyhow::Result<_> {
+            let mut catalog = BTreeMap::new();
+            for module in registry.modules() {
+                let handlers = module
+                    .pool
+                    .factory()
+                    .make_handler()?
+                    .list_handlers(module.signatures.as_deref())?;
+                let functions: Vec<_> = handlers
+                    .into_iter()
+                    .map(|handler| FunctionInfo {
+                        path: format!("/{}/{}", module.name, handler.name),
+                        kind: module.kind(&handler.name),
+                        wit: module
+                            .signatures
+                            .as_ref()
+                            .and_then(|signatures| signatures.get(&handler.name))
+                            .map(WitInfo::from),
+                        handler,
+                    })
+                    .collect();
+                catalog.insert(module.name.clone(), functions);
+            }
+            Ok(catalog)
+        })
+        .await?;
+
+    Ok(Response::from(Body::from_json(&catalog)?))
+}
+
+async fn pool_stats(req: Request<State>) -> tide::Result {
+    let stats: BTreeMap<_, _> = req
+        .state()
+        .registry
+        .modules()
+        .map(|module| (module.name.clone(), module.pool.stats()))
+        .collect();
+    Ok(Response::from(Body::from_json(&stats)?))
+}
+
+async fn call_stats(req: Request<State>) -> tide::Result {
+    let stats: BTreeMap<_, _> = req
+        .state()
+        .registry
+        .modules()
+        .map(|module| (module.name.clone(), module.stats.snapshot()))
+        .collect();
+    Ok(Response::from(Body::from_json(&stats)?))
+}
+
+async fn healthz(_req: Request<State>) -> tide::Result {
+    Ok(Response::from("ok"))
+}
+
+async fn metrics(req: Request<State>) -> tide::Result {
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(metrics::render(&req.state().registry));
+    Ok(res)
+}
+
+/// Statements declaring every function as an external function, pointing at
+/// the address the client reached the debugger at.
+async fn external_sql(req: Request<State>) -> tide::Result {
+    let host = req
+        .host()
+        .or_else(|| req.local_addr())
+        .unwrap_or_default()
+        .to_string();
+    let registry = req.state().registry.clone();
+    let sql = req
+        .state()
+        .run_guest(move || sql::external_functions(&registry, &host))
+        .await?;
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(sql);
+    Ok(res)
+}
+
+async fn drop_sql(req: Request<State>) -> tide::Result {
+    let registry = req.state().registry.clone();
+    let sql = req
+        .state()
+        .run_guest(move || sql::drop_external_functions(&registry))
+        .await?;
+    let mut res = Response::new(StatusCode::Ok);
+    res.set_body(sql);
+    Ok(res)
+}
 
-    Ok(Response::from(Body::from_json(&Payload { data: result })?))
+fn error_response(status: StatusCode, message: String) -> Response {
+    let mut res = Response::new(status);
+    res.set_body(message);
+    res
 }
@@ -0,0 +1,111 @@
+use serde_json::{Map, Value};
+
+use crate::signature::{FunctionKind, Signature, WitType};
+
+/// Turn the output of one call into result rows, without their row ids.
+///
+/// Scalar functions always produce a single value, even when it's a list.
+/// Table functions produce a row per element of their output, with a column
+/// per field when the elements are records. When the kind of function isn't
+/// known, any list is assumed to be the output of a table function.
+pub fn shape(
+    output: Value,
+    kind: Option<FunctionKind>,
+    sig: Option<&Signature>,
+) -> Vec<Vec<Value>> {
+    let kind = match kind {
+        Some(kind) => kind,
+        None if output.is_array() => FunctionKind::Table,
+        None => FunctionKind::Scalar,
+    };
+
+    if kind == FunctionKind::Scalar {
+        return vec![vec![output]];
+    }
+
+    let record = match sig.map(|sig| &sig.result) {
+        Some(WitType::List(ty)) => match &**ty {
+            WitType::Record(record) => Some(record),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    let elements = match output {
+        Value::Array(elements) => elements,
+        output => vec![output],
+    };
+
+    elements
+        .into_iter()
+        .map(|element| match (record, element) {
+            (Some(record), Value::Object(fields)) => record
+                .fields
+                .iter()
+                .map(|(name, _)| field(&fields, name).cloned().unwrap_or(Value::Null))
+                .collect(),
+            // records serialized as sequences are already in field order
+            (Some(_), Value::Array(columns)) => columns,
+            (_, element) => vec![element],
+        })
+        .collect()
+}
+
+/// Find a record field, whichever case the guest serialized its name in.
+fn field<'a>(fields: &'a Map<String, Value>, name: &str) -> Option<&'a Value> {
+    fields
+        .get(name)
+        .or_else(|| fields.get(&name.replace('-', "_")))
+        .or_else(|| fields.get(&name.replace('_', "-")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::Record;
+    use serde_json::json;
+
+    fn sig(result: WitType) -> Signature {
+        Signature {
+            name: "f".into(),
+            params: vec![],
+            result,
+        }
+    }
+
+    #[test]
+    fn test_scalar_list_stays_one_value() {
+        let sig = sig(WitType::List(Box::new(WitType::String)));
+        let rows = shape(json!(["a", "b"]), Some(sig.kind()), Some(&sig));
+        assert_eq!(rows, vec![vec![json!(["a", "b"])]]);
+    }
+
+    #[test]
+    fn test_table_of_records() {
+        let sig = sig(WitType::List(Box::new(WitType::Record(Record {
+            name: "user".into(),
+            fields: vec![
+                ("first-name".into(), WitType::String),
+                ("uid".into(), WitType::S32),
+            ],
+        }))));
+        let rows = shape(
+            json!([{"uid": 1, "first_name": "a"}, {"uid": 2}]),
+            Some(sig.kind()),
+            Some(&sig),
+        );
+        assert_eq!(
+            rows,
+            vec![vec![json!("a"), json!(1)], vec![Value::Null, json!(2)]]
+        );
+    }
+
+    #[test]
+    fn test_unknown_kind() {
+        assert_eq!(
+            shape(json!([1, 2]), None, None),
+            vec![vec![json!(1)], vec![json!(2)]]
+        );
+        assert_eq!(shape(json!(1), None, None), vec![vec![json!(1)]]);
+    }
+}
@@ -0,0 +1,213 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use wit_parser::{Interface, Type, TypeDefKind};
+
+/// A WIT type, flattened out of the parsed interface so the rest of the
+/// debugger doesn't need to chase type ids.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WitType {
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    S8,
+    S16,
+    S32,
+    S64,
+    Float32,
+    Float64,
+    Char,
+    String,
+    List(Box<WitType>),
+    Option(Box<WitType>),
+    Tuple(Vec<WitType>),
+    Record(Record),
+    Other(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub fields: Vec<(String, WitType)>,
+}
+
+impl fmt::Display for WitType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WitType::Bool => write!(f, "bool"),
+            WitType::U8 => write!(f, "u8"),
+            WitType::U16 => write!(f, "u16"),
+            WitType::U32 => write!(f, "u32"),
+            WitType::U64 => write!(f, "u64"),
+            WitType::S8 => write!(f, "s8"),
+            WitType::S16 => write!(f, "s16"),
+            WitType::S32 => write!(f, "s32"),
+            WitType::S64 => write!(f, "s64"),
+            WitType::Float32 => write!(f, "float32"),
+            WitType::Float64 => write!(f, "float64"),
+            WitType::Char => write!(f, "char"),
+            WitType::String => write!(f, "string"),
+            WitType::List(t) => write!(f, "list<{}>", t),
+            WitType::Option(t) => write!(f, "option<{}>", t),
+            WitType::Tuple(ts) => {
+                write!(f, "tuple<")?;
+                for (i, t) in ts.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", t)?;
+                }
+                write!(f, ">")
+            }
+            WitType::Record(r) => write!(f, "{}", r.name),
+            WitType::Other(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Whether a function is called as a scalar UDF or as a table-valued
+/// function (TVF) producing any number of rows.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum FunctionKind {
+    #[serde(rename = "udf")]
+    Scalar,
+    #[serde(rename = "tvf")]
+    Table,
+}
+
+impl FromStr for FunctionKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "udf" | "scalar" => Ok(FunctionKind::Scalar),
+            "tvf" | "table" => Ok(FunctionKind::Table),
+            _ => Err(anyhow!(
+                "unknown function kind {:?}, expected udf or tvf",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Signature {
+    pub name: String,
+    pub params: Vec<(String, WitType)>,
+    pub result: WitType,
+}
+
+impl Signature {
+    /// Functions returning a list of records are table-valued, since that's
+    /// the only way to return rows with several columns. Anything else,
+    /// including other lists, is assumed to be a scalar function.
+    pub fn kind(&self) -> FunctionKind {
+        match &self.result {
+            WitType::List(ty) if matches!(**ty, WitType::Record(_)) => FunctionKind::Table,
+            _ => FunctionKind::Scalar,
+        }
+    }
+
+    /// The types of the columns in each result row when called as `kind`.
+    pub fn result_columns(&self, kind: FunctionKind) -> Vec<WitType> {
+        match (kind, &self.result) {
+            (FunctionKind::Table, WitType::List(ty)) => match &**ty {
+                WitType::Record(record) => record.fields.iter().map(|(_, ty)| ty.clone()).collect(),
+                ty => vec![ty.clone()],
+            },
+            (_, ty) => vec![ty.clone()],
+        }
+    }
+}
+
+/// The signatures of every function declared in a WIT file, in the order
+/// they're declared.
+#[derive(Clone, Debug, Default)]
+pub struct Signatures {
+    functions: Vec<Signature>,
+}
+
+impl Signatures {
+    pub fn from_file(wit_path: impl AsRef<Path>) -> Result<Self> {
+        let iface = Interface::parse_file(wit_path)?;
+
+        let functions = iface
+            .functions
+            .iter()
+            .map(|func| Signature {
+                name: func.name.clone(),
+                params: func
+                    .params
+                    .iter()
+                    .map(|(name, ty)| (name.clone(), convert(&iface, ty)))
+                    .collect(),
+                result: convert(&iface, &func.result),
+            })
+            .collect();
+
+        Ok(Self { functions })
+    }
+
+    /// Look up a function by either its WIT name (`power-of`) or the name of
+    /// the rust method implementing it (`power_of`).
+    pub fn get(&self, name: &str) -> Option<&Signature> {
+        let name = handler_name(name);
+        self.functions
+            .iter()
+            .find(|sig| handler_name(&sig.name) == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Signature> {
+        self.functions.iter()
+    }
+}
+
+/// WIT names are kebab-case while the debug handlers are named after the
+/// snake_case rust methods generated by wit-bindgen.
+pub fn handler_name(wit_name: &str) -> String {
+    wit_name.replace('-', "_")
+}
+
+fn convert(iface: &Interface, ty: &Type) -> WitType {
+    match ty {
+        Type::Bool => WitType::Bool,
+        Type::U8 => WitType::U8,
+        Type::U16 => WitType::U16,
+        Type::U32 => WitType::U32,
+        Type::U64 => WitType::U64,
+        Type::S8 => WitType::S8,
+        Type::S16 => WitType::S16,
+        Type::S32 => WitType::S32,
+        Type::S64 => WitType::S64,
+        Type::Float32 => WitType::Float32,
+        Type::Float64 => WitType::Float64,
+        Type::Char => WitType::Char,
+        Type::String => WitType::String,
+        Type::Id(id) => {
+            let def = &iface.types[*id];
+            match &def.kind {
+                TypeDefKind::Type(t) => convert(iface, t),
+                TypeDefKind::List(t) => WitType::List(Box::new(convert(iface, t))),
+                TypeDefKind::Option(t) => WitType::Option(Box::new(convert(iface, t))),
+                TypeDefKind::Tuple(t) => {
+                    WitType::Tuple(t.types.iter().map(|t| convert(iface, t)).collect())
+                }
+                TypeDefKind::Record(r) => WitType::Record(Record {
+                    name: def.name.clone().unwrap_or_else(|| "record".into()),
+                    fields: r
+                        .fields
+                        .iter()
+                        .map(|f| (f.name.clone(), convert(iface, &f.ty)))
+                        .collect(),
+                }),
+                _ => WitType::Other(def.name.clone().unwrap_or_else(|| "unknown".into())),
+            }
+        }
+        other => WitType::Other(format!("{:?}", other).to_lowercase()),
+    }
+}
@@ -0,0 +1,120 @@
+//! Snapshots of an instance after it has initialized itself, so new instances
+//! can start from there rather than doing the same work again.
+
+use anyhow::{anyhow, Result};
+use wasmtime::{Extern, Instance, Mutability, Store, Val};
+
+const PAGE_SIZE: usize = 65536;
+
+/// The exported memories and mutable globals of an instance.
+///
+/// Globals the module doesn't export can't be read, so they start from their
+/// initial values. For modules built by Rust or C toolchains that's only the
+/// stack pointer, which is back where it started once a call has returned.
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    memories: Vec<(String, Vec<u8>)>,
+    globals: Vec<(String, Val)>,
+}
+
+impl Snapshot {
+    pub fn capture<T>(store: &mut Store<T>, instance: &Instance) -> Self {
+        let exports: Vec<_> = instance
+            .exports(&mut *store)
+            .map(|export| (export.name().to_string(), export.into_extern()))
+            .collect();
+
+        let mut snapshot = Snapshot::default();
+        for (name, export) in exports {
+            match export {
+                Extern::Memory(memory) => {
+                    let data = memory.data(&*store).to_vec();
+                    snapshot.memories.push((name, data));
+                }
+                Extern::Global(global) if global.ty(&*store).mutability() == Mutability::Var => {
+                    // references belong to the store they were made in, so
+                    // only numbers can be carried over
+                    if let value @ (Val::I32(_) | Val::I64(_) | Val::F32(_) | Val::F64(_)) =
+                        global.get(&mut *store)
+                    {
+                        snapshot.globals.push((name, value));
+                    }
+                }
+                _ => {}
+            }
+        }
+        snapshot
+    }
+
+    /// Bring a new instance of the same module to the snapshotted state.
+    pub fn restore<T>(&self, store: &mut Store<T>, instance: &Instance) -> Result<()> {
+        for (name, data) in &self.memories {
+            let memory = instance
+                .get_memory(&mut *store, name)
+                .ok_or_else(|| anyhow!("the snapshot's memory {} is missing", name))?;
+            let missing = data.len().saturating_sub(memory.data_size(&*store));
+            if missing > 0 {
+                memory.grow(&mut *store, (missing / PAGE_SIZE) as u64)?;
+            }
+            memory.data_mut(&mut *store)[..data.len()].copy_from_slice(data);
+        }
+        for (name, value) in &self.globals {
+            instance
+                .get_global(&mut *store, name)
+                .ok_or_else(|| anyhow!("the snapshot's global {} is missing", name))?
+                .set(&mut *store, value.clone())?;
+        }
+        Ok(())
+    }
+
+    /// How many bytes of memory new instances are initialized with.
+    pub fn size(&self) -> usize {
+        self.memories.iter().map(|(_, data)| data.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasmtime::{Engine, Module};
+
+    /// Grows its memory by two pages, writes to the last one and counts the
+    /// calls to `init`.
+    const INIT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $calls (export "calls") (mut i32) (i32.const 0))
+          (global (export "pages") i32 (i32.const 1))
+          (func (export "init")
+            (drop (memory.grow (i32.const 2)))
+            (i32.store (i32.const 131072) (i32.const 42))
+            (global.set $calls (i32.add (global.get $calls) (i32.const 1)))))
+    "#;
+
+    #[test]
+    fn test_round_trip() {
+        let engine = Engine::default();
+        let module = Module::new(&engine, INIT).unwrap();
+
+        let mut store = Store::new(&engine, ());
+        let initialized = Instance::new(&mut store, &module, &[]).unwrap();
+        initialized
+            .get_typed_func::<(), (), _>(&mut store, "init")
+            .unwrap()
+            .call(&mut store, ())
+            .unwrap();
+        let snapshot = Snapshot::capture(&mut store, &initialized);
+        assert_eq!(snapshot.size(), 3 * PAGE_SIZE);
+        // the immutable global is left alone
+        assert_eq!(snapshot.globals.len(), 1);
+
+        let mut store = Store::new(&engine, ());
+        let fresh = Instance::new(&mut store, &module, &[]).unwrap();
+        snapshot.restore(&mut store, &fresh).unwrap();
+        let memory = fresh.get_memory(&mut store, "memory").unwrap();
+        assert_eq!(memory.data_size(&store), 3 * PAGE_SIZE);
+        assert_eq!(memory.data(&store)[131072], 42);
+        let calls = fresh.get_global(&mut store, "calls").unwrap();
+        assert_eq!(calls.get(&mut store).i32(), Some(1));
+    }
+}
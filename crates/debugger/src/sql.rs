@@ -0,0 +1,322 @@
+//! SQL for creating the functions a WIT file declares in SingleStoreDB,
+//! either from the module itself or as external functions served by the
+//! debugger.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::SqlArgs;
+use crate::registry::Registry;
+use crate::signature::{handler_name, FunctionKind, Signature, Signatures, WitType};
+
+/// Where SingleStoreDB loads a module and its WIT file from.
+pub enum Source {
+    /// Files on the client's machine, sent with `LOCAL INFILE`.
+    LocalInfile { wasm: PathBuf, wit: PathBuf },
+    /// The contents of the files themselves, inlined as base64.
+    Base64 { wasm: Vec<u8>, wit: Vec<u8> },
+}
+
+impl Source {
+    fn clauses(&self) -> (String, String) {
+        match self {
+            Source::LocalInfile { wasm, wit } => (
+                format!(
+                    "LOCAL INFILE {}",
+                    string_literal(&wasm.display().to_string())
+                ),
+                format!(
+                    "LOCAL INFILE {}",
+                    string_literal(&wit.display().to_string())
+                ),
+            ),
+            Source::Base64 { wasm, wit } => (
+                format!("BASE64 '{}'", base64::encode(wasm)),
+                format!("BASE64 '{}'", base64::encode(wit)),
+            ),
+        }
+    }
+}
+
+/// Print a `CREATE FUNCTION` statement for every function in a WIT file.
+pub fn run(args: &SqlArgs) -> Result<()> {
+    let signatures = Signatures::from_file(&args.wit)?;
+    let source = if args.base64 {
+        Source::Base64 {
+            wasm: read(&args.wasm)?,
+            wit: read(&args.wit)?,
+        }
+    } else {
+        Source::LocalInfile {
+            wasm: args.wasm.clone(),
+            wit: args.wit.clone(),
+        }
+    };
+
+    for sig in signatures.iter() {
+        println!("{}\n", create_wasm_function(sig, &source, args.or_replace));
+    }
+    Ok(())
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+/// A `CREATE FUNCTION ... AS WASM` statement, preceded by a comment giving
+/// the function's signature in SQL types. Functions returning a list of
+/// records are created as table functions.
+pub fn create_wasm_function(sig: &Signature, source: &Source, or_replace: bool) -> String {
+    let kind = sig.kind();
+    let (wasm, wit) = source.clauses();
+    format!(
+        "-- {}\nCREATE {}FUNCTION {}{} AS WASM\nFROM {}\nWITH WIT FROM {};",
+        signature(sig, kind),
+        if or_replace { "OR REPLACE " } else { "" },
+        sql_name(&sig.name),
+        match kind {
+            FunctionKind::Scalar => "",
+            FunctionKind::Table => " RETURNS TABLE",
+        },
+        wasm,
+        wit
+    )
+}
+
+/// The parameters and return type of a function in SQL, e.g.
+/// `power_of(b INT NOT NULL, exp INT NOT NULL) RETURNS INT NOT NULL`.
+pub fn signature(sig: &Signature, kind: FunctionKind) -> String {
+    let params: Vec<_> = sig
+        .params
+        .iter()
+        .map(|(name, ty)| format!("{} {}", sql_name(name), column_type(ty)))
+        .collect();
+    format!(
+        "{}({}) RETURNS {}",
+        sql_name(&sig.name),
+        params.join(", "),
+        returns(sig, kind)
+    )
+}
+
+fn returns(sig: &Signature, kind: FunctionKind) -> String {
+    match (kind, &sig.result) {
+        (FunctionKind::Table, WitType::List(ty)) => {
+            let columns: Vec<_> = match &**ty {
+                WitType::Record(record) => record
+                    .fields
+                    .iter()
+                    .map(|(name, ty)| format!("{} {}", sql_name(name), column_type(ty)))
+                    .collect(),
+                ty => vec![format!("value {}", column_type(ty))],
+            };
+            format!("TABLE({})", columns.join(", "))
+        }
+        (_, ty) => column_type(ty),
+    }
+}
+
+/// A `CREATE EXTERNAL FUNCTION` statement for a function served at
+/// `service`, which is of the form `host:port/path`.
+pub fn create_external_function(sig: &Signature, kind: FunctionKind, service: &str) -> String {
+    format!(
+        "CREATE OR REPLACE EXTERNAL FUNCTION {}\nAS REMOTE SERVICE {} FORMAT JSON;",
+        signature(sig, kind),
+        string_literal(service)
+    )
+}
+
+pub fn drop_function(wit_name: &str) -> String {
+    format!("DROP FUNCTION IF EXISTS {};", sql_name(wit_name))
+}
+
+/// The statements declaring every function the debugger serves as an
+/// external function, with `host` being the address SingleStoreDB reaches
+/// the debugger at. Functions of modules without a WIT file have unknown SQL
+/// types, so they're only mentioned in a comment.
+pub fn external_functions(registry: &Registry, host: &str) -> Result<String> {
+    let mut sql = String::new();
+    for module in registry.modules() {
+        for handler in module
+            .pool
+            .factory()
+            .make_handler()?
+            .list_handlers(module.signatures.as_deref())?
+        {
+            let service = format!("{}/{}/{}", host, module.name, handler.name);
+            match module.signature(&handler.name) {
+                Ok(Some(sig)) => {
+                    let kind = module.kind(&handler.name).unwrap_or_else(|| sig.kind());
+                    sql += &create_external_function(sig, kind, &service);
+                }
+                _ => {
+                    sql += &format!(
+                        "-- {}/{} has no WIT signature, so its SQL types are unknown\n\
+                         -- ... AS REMOTE SERVICE {} FORMAT JSON;",
+                        module.name,
+                        handler.name,
+                        string_literal(&service)
+                    );
+                }
+            }
+            sql += "\n\n";
+        }
+    }
+    Ok(sql)
+}
+
+/// The statements dropping every external function the debugger serves, so
+/// that the wasm versions can be created again.
+pub fn drop_external_functions(registry: &Registry) -> Result<String> {
+    let mut sql = String::new();
+    for module in registry.modules() {
+        for handler in module
+            .pool
+            .factory()
+            .make_handler()?
+            .list_handlers(module.signatures.as_deref())?
+        {
+            sql += &drop_function(&handler.name);
+            sql += "\n";
+        }
+    }
+    Ok(sql)
+}
+
+/// The SQL type of a value, with `option`s being the only nullable types.
+pub fn column_type(ty: &WitType) -> String {
+    match ty {
+        WitType::Option(ty) => format!("{} NULL", sql_type(ty)),
+        ty => format!("{} NOT NULL", sql_type(ty)),
+    }
+}
+
+/// The SingleStoreDB type holding values of a WIT type. Values without a
+/// SQL counterpart, such as records and most lists, are passed as JSON.
+pub fn sql_type(ty: &WitType) -> String {
+    match ty {
+        WitType::Bool => "BOOL",
+        WitType::S8 => "TINYINT",
+        WitType::U8 => "TINYINT UNSIGNED",
+        WitType::S16 => "SMALLINT",
+        WitType::U16 => "SMALLINT UNSIGNED",
+        WitType::S32 => "INT",
+        WitType::U32 => "INT UNSIGNED",
+        WitType::S64 => "BIGINT",
+        WitType::U64 => "BIGINT UNSIGNED",
+        WitType::Float32 => "FLOAT",
+        WitType::Float64 => "DOUBLE",
+        WitType::Char => "CHAR(1)",
+        WitType::String => "TEXT",
+        WitType::List(ty) if **ty == WitType::U8 => "BLOB",
+        WitType::Option(ty) => return sql_type(ty),
+        WitType::List(_) | WitType::Tuple(_) | WitType::Record(_) | WitType::Other(_) => "JSON",
+    }
+    .to_string()
+}
+
+/// The SQL name of a WIT function, parameter or field: kebab-case becomes
+/// snake_case, and anything that still isn't a plain identifier is quoted.
+pub fn sql_name(wit_name: &str) -> String {
+    let name = handler_name(wit_name);
+    let plain = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if plain {
+        name
+    } else {
+        format!("`{}`", name.replace('`', "``"))
+    }
+}
+
+pub fn string_literal(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::signature::Record;
+
+    fn split_str() -> Signature {
+        Signature {
+            name: "split-str".into(),
+            params: vec![
+                ("phrase".into(), WitType::String),
+                ("delim".into(), WitType::String),
+            ],
+            result: WitType::List(Box::new(WitType::Record(Record {
+                name: "subphrase".into(),
+                fields: vec![
+                    ("str".into(), WitType::String),
+                    ("idx".into(), WitType::S32),
+                ],
+            }))),
+        }
+    }
+
+    #[test]
+    fn test_sql_types() {
+        assert_eq!(column_type(&WitType::U64), "BIGINT UNSIGNED NOT NULL");
+        assert_eq!(
+            column_type(&WitType::Option(Box::new(WitType::Float64))),
+            "DOUBLE NULL"
+        );
+        assert_eq!(sql_type(&WitType::List(Box::new(WitType::U8))), "BLOB");
+        assert_eq!(sql_type(&WitType::List(Box::new(WitType::S32))), "JSON");
+    }
+
+    #[test]
+    fn test_sql_names() {
+        assert_eq!(sql_name("split-str"), "split_str");
+        assert_eq!(sql_name("first-name"), "first_name");
+        assert_eq!(sql_name("2d"), "`2d`");
+    }
+
+    #[test]
+    fn test_create_table_function() {
+        let source = Source::LocalInfile {
+            wasm: "split.wasm".into(),
+            wit: "split.wit".into(),
+        };
+        assert_eq!(
+            create_wasm_function(&split_str(), &source, false),
+            "-- split_str(phrase TEXT NOT NULL, delim TEXT NOT NULL) \
+             RETURNS TABLE(str TEXT NOT NULL, idx INT NOT NULL)\n\
+             CREATE FUNCTION split_str RETURNS TABLE AS WASM\n\
+             FROM LOCAL INFILE 'split.wasm'\n\
+             WITH WIT FROM LOCAL INFILE 'split.wit';"
+        );
+
+        let source = Source::Base64 {
+            wasm: b"\0asm".to_vec(),
+            wit: b"f: func()".to_vec(),
+        };
+        let sql = create_wasm_function(&split_str(), &source, true);
+        assert!(sql.contains("CREATE OR REPLACE FUNCTION split_str RETURNS TABLE AS WASM\n"));
+        assert!(sql.contains("FROM BASE64 'AGFzbQ=='\nWITH WIT FROM BASE64 'ZjogZnVuYygp';"));
+    }
+
+    #[test]
+    fn test_external_function() {
+        let power_of = Signature {
+            name: "power-of".into(),
+            params: vec![("b".into(), WitType::S32), ("exp".into(), WitType::S32)],
+            result: WitType::S32,
+        };
+        assert_eq!(
+            create_external_function(
+                &power_of,
+                FunctionKind::Scalar,
+                "127.0.0.1:3000/power/power_of"
+            ),
+            "CREATE OR REPLACE EXTERNAL FUNCTION \
+             power_of(b INT NOT NULL, exp INT NOT NULL) RETURNS INT NOT NULL\n\
+             AS REMOTE SERVICE '127.0.0.1:3000/power/power_of' FORMAT JSON;"
+        );
+        assert_eq!(
+            drop_function("power-of"),
+            "DROP FUNCTION IF EXISTS power_of;"
+        );
+    }
+}
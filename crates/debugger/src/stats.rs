@@ -0,0 +1,276 @@
+//! Call counts and latencies of every function, to see which ones dominate
+//! a batch.
+
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::coerce::ArgError;
+use crate::handle::GuestError;
+use crate::registry::Registry;
+use crate::signature::handler_name;
+
+/// A latency histogram with fixed buckets, from 10µs up to a second.
+#[derive(Clone, Debug)]
+pub struct Histogram {
+    /// Calls in each bucket, the last one counting everything slower than
+    /// the largest bound.
+    counts: Vec<u64>,
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    /// Upper bounds of the buckets, in microseconds.
+    pub const BOUNDS_US: &'static [u64] = &[
+        10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000,
+        500_000, 1_000_000,
+    ];
+
+    pub fn record(&mut self, elapsed: Duration) {
+        let micros = elapsed.as_micros() as u64;
+        let bucket = Self::BOUNDS_US
+            .iter()
+            .position(|bound| micros <= *bound)
+            .unwrap_or(Self::BOUNDS_US.len());
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += elapsed;
+        self.max = self.max.max(elapsed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    pub fn mean(&self) -> Duration {
+        match self.count {
+            0 => Duration::ZERO,
+            count => Duration::from_secs_f64(self.sum.as_secs_f64() / count as f64),
+        }
+    }
+
+    /// The upper bound of the bucket holding the `q`th quantile, or the
+    /// slowest call when that's in the last bucket.
+    pub fn quantile(&self, q: f64) -> Duration {
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bound, count) in Self::BOUNDS_US.iter().zip(&self.counts) {
+            seen += count;
+            if seen >= rank {
+                return Duration::from_micros(*bound).min(self.max);
+            }
+        }
+        self.max
+    }
+
+    /// Cumulative counts of calls taking at most each bound.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        Self::BOUNDS_US
+            .iter()
+            .zip(&self.counts)
+            .scan(0, |seen, (bound, count)| {
+                *seen += count;
+                Some((Duration::from_micros(*bound), *seen))
+            })
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; Self::BOUNDS_US.len() + 1],
+            count: 0,
+            sum: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl Serialize for Histogram {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Summary {
+            mean_us: u128,
+            p50_us: u128,
+            p90_us: u128,
+            p99_us: u128,
+            max_us: u128,
+            buckets: Vec<Bucket>,
+        }
+
+        #[derive(Serialize)]
+        struct Bucket {
+            le_us: u128,
+            count: u64,
+        }
+
+        Summary {
+            mean_us: self.mean().as_micros(),
+            p50_us: self.quantile(0.5).as_micros(),
+            p90_us: self.quantile(0.9).as_micros(),
+            p99_us: self.quantile(0.99).as_micros(),
+            max_us: self.max.as_micros(),
+            buckets: self
+                .buckets()
+                .map(|(bound, count)| Bucket {
+                    le_us: bound.as_micros(),
+                    count,
+                })
+                .collect(),
+        }
+        .serialize(serializer)
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct FunctionStats {
+    pub requests: u64,
+    /// Guest calls, one per row.
+    pub calls: u64,
+    /// Failed calls by kind: `argument` when the arguments don't match the
+    /// WIT signature, `trap` when the guest trapped, or `other`.
+    pub errors: BTreeMap<String, u64>,
+    /// Traps by kind, e.g. `panic`, `fuel` or `memory_out_of_bounds`.
+    pub traps: BTreeMap<String, u64>,
+    pub latency: Histogram,
+}
+
+impl FunctionStats {
+    pub fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+}
+
+/// Statistics of the guest calls to every function of a module, one call
+/// per row.
+#[derive(Debug, Default)]
+pub struct Stats {
+    functions: Mutex<BTreeMap<String, FunctionStats>>,
+}
+
+impl Stats {
+    pub fn record_request(&self, function: &str) {
+        let mut functions = self.functions.lock().unwrap();
+        functions
+            .entry(handler_name(function))
+            .or_default()
+            .requests += 1;
+    }
+
+    pub fn record(&self, function: &str, elapsed: Duration, error: Option<&anyhow::Error>) {
+        let mut functions = self.functions.lock().unwrap();
+        let stats = functions.entry(handler_name(function)).or_default();
+        stats.calls += 1;
+        stats.latency.record(elapsed);
+
+        let error = match error {
+            Some(error) => error,
+            None => return,
+        };
+        let kind = if error.is::<ArgError>() {
+            "argument"
+        } else if let Some(guest) = error.downcast_ref::<GuestError>() {
+            *stats.traps.entry(guest.kind.clone()).or_default() += 1;
+            "trap"
+        } else {
+            "other"
+        };
+        *stats.errors.entry(kind.to_string()).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<String, FunctionStats> {
+        self.functions.lock().unwrap().clone()
+    }
+}
+
+/// A table of every function called so far, the ones taking the most time
+/// first, or nothing when no function was called.
+pub fn report(registry: &Registry) -> String {
+    let mut rows: Vec<_> = registry
+        .modules()
+        .flat_map(|module| {
+            module
+                .stats
+                .snapshot()
+                .into_iter()
+                .map(move |(function, stats)| (format!("{}/{}", module.name, function), stats))
+        })
+        .collect();
+    if rows.is_empty() {
+        return String::new();
+    }
+    rows.sort_by_key(|(_, stats)| Reverse(stats.latency.sum()));
+    let total: Duration = rows.iter().map(|(_, stats)| stats.latency.sum()).sum();
+
+    let mut out = format!(
+        "{:<32} {:>8} {:>7} {:>10} {:>6} {:>10} {:>10} {:>10} {:>10}\n",
+        "function", "calls", "errors", "total", "share", "mean", "p50", "p99", "max"
+    );
+    for (name, stats) in &rows {
+        let latency = &stats.latency;
+        let _ = writeln!(
+            out,
+            "{:<32} {:>8} {:>7} {:>10.1?} {:>5.1}% {:>10.1?} {:>10.1?} {:>10.1?} {:>10.1?}",
+            name,
+            stats.calls,
+            stats.error_count(),
+            latency.sum(),
+            100.0 * latency.sum().as_secs_f64() / total.as_secs_f64().max(f64::EPSILON),
+            latency.mean(),
+            latency.quantile(0.5),
+            latency.quantile(0.99),
+            latency.max,
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        for micros in [5, 20, 20, 40, 3_000_000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        assert_eq!(histogram.count, 5);
+        assert!((histogram.mean().as_secs_f64() - 0.600_017).abs() < 1e-9);
+        assert_eq!(histogram.quantile(0.5), Duration::from_micros(25));
+        assert_eq!(histogram.quantile(0.8), Duration::from_micros(50));
+        assert_eq!(histogram.quantile(1.0), Duration::from_secs(3));
+        let buckets: Vec<_> = histogram.buckets().take(3).map(|(_, n)| n).collect();
+        assert_eq!(buckets, [1, 3, 4]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let stats = Stats::default();
+        stats.record_request("power-of");
+        stats.record("power-of", Duration::from_micros(10), None);
+        stats.record(
+            "power_of",
+            Duration::from_micros(30),
+            Some(&anyhow!("oops")),
+        );
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot["power_of"].requests, 1);
+        assert_eq!(snapshot["power_of"].calls, 2);
+        assert_eq!(snapshot["power_of"].errors["other"], 1);
+        assert_eq!(
+            snapshot["power_of"].latency.mean(),
+            Duration::from_micros(20)
+        );
+    }
+}
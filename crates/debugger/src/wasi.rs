@@ -0,0 +1,478 @@
+use anyhow::{anyhow, Context, Result};
+use cap_std::fs::Dir;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde_json::Value;
+use std::any::Any;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use wasi_common::clocks::{WasiMonotonicClock, WasiSystemClock};
+use wasi_common::dir::{ReaddirCursor, ReaddirEntity, WasiDir};
+use wasi_common::file::{FdFlags, Filestat, OFlags, WasiFile};
+use wasi_common::{Error, ErrorExt, SystemTimeSpec, WasiCtx};
+use wasmtime_wasi::sync::WasiCtxBuilder;
+
+/// What the guest sees of its WASI environment, besides stdout and stderr
+/// which the debugger always captures.
+#[derive(Clone, Debug, Default)]
+pub struct WasiOptions {
+    /// Environment variables, as `(name, value)` pairs.
+    pub env: Vec<(String, String)>,
+    /// Arguments passed after `argv[0]`, which is the module's file name.
+    pub args: Vec<String>,
+    /// Host directories the guest can open files in.
+    pub dirs: Vec<Preopen>,
+    pub clock: Clock,
+    pub random: Random,
+    /// Restart the clocks and random numbers before every row, so what a
+    /// guest computes only depends on its arguments, the seed and the row's
+    /// id.
+    pub deterministic: bool,
+}
+
+/// A host directory made available to the guest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Preopen {
+    pub host: PathBuf,
+    /// The path the guest opens the directory by.
+    pub guest: String,
+    /// Whether the guest may create, change and delete files in it.
+    pub writable: bool,
+}
+
+impl Preopen {
+    /// Parse `HOST[::GUEST]`, where the guest path defaults to the host one.
+    pub fn parse(arg: &str, writable: bool) -> Result<Self> {
+        let (host, guest) = match arg.split_once("::") {
+            Some((host, guest)) => (host, guest),
+            None => (arg, arg),
+        };
+        if host.is_empty() || guest.is_empty() {
+            return Err(anyhow!("expected HOST[::GUEST], found {:?}", arg));
+        }
+        Ok(Self {
+            host: host.into(),
+            guest: guest.into(),
+            writable,
+        })
+    }
+}
+
+/// What the guest's clocks tell it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clock {
+    /// The host's clocks.
+    Real,
+    /// The same time on every read: this many seconds after the unix epoch,
+    /// and a monotonic clock that never advances.
+    Fixed(u64),
+    /// Clocks starting at the unix epoch and advancing by this much every
+    /// time one of them is read.
+    Stepped(Duration),
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock::Real
+    }
+}
+
+impl FromStr for Clock {
+    type Err = anyhow::Error;
+
+    /// `real`, `fixed` for the unix epoch, `fixed:SECONDS`, `stepped` for a
+    /// millisecond per read, or `stepped:MILLIS`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "real" => Ok(Clock::Real),
+            None if s == "fixed" => Ok(Clock::Fixed(0)),
+            None if s == "stepped" => Ok(Clock::Stepped(Duration::from_millis(1))),
+            Some(("fixed", secs)) => {
+                Ok(Clock::Fixed(secs.parse().with_context(|| {
+                    format!("invalid fixed time {:?}", secs)
+                })?))
+            }
+            Some(("stepped", millis)) => Ok(Clock::Stepped(Duration::from_millis(
+                millis
+                    .parse()
+                    .with_context(|| format!("invalid clock step {:?}", millis))?,
+            ))),
+            _ => Err(anyhow!(
+                "unknown clock {:?}, expected real, fixed, fixed:SECONDS, stepped or stepped:MILLIS",
+                s
+            )),
+        }
+    }
+}
+
+/// Where the guest's random numbers come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Random {
+    /// The host's secure random number generator.
+    Real,
+    /// A generator seeded the same way for every instance, so they all see
+    /// the same numbers.
+    Seeded(u64),
+}
+
+impl Default for Random {
+    fn default() -> Self {
+        Random::Real
+    }
+}
+
+impl Random {
+    fn seed(self) -> Option<u64> {
+        match self {
+            Random::Real => None,
+            Random::Seeded(seed) => Some(seed),
+        }
+    }
+}
+
+impl FromStr for Random {
+    type Err = anyhow::Error;
+
+    /// `real` or `seed:N`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "real" => Ok(Random::Real),
+            Some(("seed", seed)) => Ok(Random::Seeded(
+                seed.parse()
+                    .with_context(|| format!("invalid seed {:?}", seed))?,
+            )),
+            _ => Err(anyhow!("unknown random {:?}, expected real or seed:N", s)),
+        }
+    }
+}
+
+impl WasiOptions {
+    pub fn build(
+        &self,
+        wasm_path: &Path,
+        stdout: Box<dyn WasiFile>,
+        stderr: Box<dyn WasiFile>,
+    ) -> Result<WasiCtx> {
+        let argv0 = wasm_path
+            .file_name()
+            .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+
+        let mut builder = WasiCtxBuilder::new()
+            .arg(&argv0)?
+            .stdout(stdout)
+            .stderr(stderr);
+        for arg in &self.args {
+            builder = builder.arg(arg)?;
+        }
+        for (name, value) in &self.env {
+            builder = builder.env(name, value)?;
+        }
+        let mut ctx = builder.build();
+
+        for preopen in &self.dirs {
+            let dir = Dir::open_ambient_dir(&preopen.host, cap_std::ambient_authority())
+                .with_context(|| format!("failed to open {}", preopen.host.display()))?;
+            let dir: Box<dyn WasiDir> = Box::new(wasmtime_wasi::sync::Dir::from_cap_std(dir));
+            let dir = if preopen.writable {
+                dir
+            } else {
+                Box::new(ReadOnlyDir(dir))
+            };
+            ctx.push_preopened_dir(dir, &preopen.guest)?;
+        }
+
+        self.install(&mut ctx, self.random.seed());
+        Ok(ctx)
+    }
+
+    /// Give a deterministic guest fresh clocks and random numbers before it
+    /// computes a row, the numbers coming from `seed` when given rather than
+    /// from the configured one. Does nothing otherwise.
+    pub fn restart(&self, ctx: &mut WasiCtx, seed: Option<u64>, row_id: &Value) {
+        if self.deterministic {
+            let seed = seed.or_else(|| self.random.seed()).unwrap_or_default();
+            // rows are seeded by their id rather than their position, so rows
+            // with the same arguments still get their own numbers, and a row
+            // sent on its own gets the numbers it got in its batch
+            self.install(ctx, Some(seed.wrapping_add(row_key(row_id))));
+        }
+    }
+
+    fn install(&self, ctx: &mut WasiCtx, seed: Option<u64>) {
+        match self.clock {
+            Clock::Real => {}
+            Clock::Fixed(secs) => {
+                ctx.clocks.system = Box::new(FixedSystemClock(
+                    SystemTime::UNIX_EPOCH + Duration::from_secs(secs),
+                ));
+                ctx.clocks.monotonic = Box::new(FixedMonotonicClock(ctx.clocks.creation_time));
+            }
+            Clock::Stepped(step) => {
+                let clock = SteppedClock {
+                    step,
+                    reads: Arc::default(),
+                };
+                ctx.clocks.system = Box::new(clock.clone());
+                ctx.clocks.monotonic =
+                    Box::new(SteppedMonotonicClock(clock, ctx.clocks.creation_time));
+            }
+        }
+        if let Some(seed) = seed {
+            ctx.random = Box::new(StdRng::seed_from_u64(seed));
+        }
+    }
+}
+
+struct FixedSystemClock(SystemTime);
+
+impl WasiSystemClock for FixedSystemClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self, _precision: Duration) -> cap_std::time::SystemTime {
+        cap_std::time::SystemTime::from_std(self.0)
+    }
+}
+
+struct FixedMonotonicClock(cap_std::time::Instant);
+
+impl WasiMonotonicClock for FixedMonotonicClock {
+    fn resolution(&self) -> Duration {
+        Duration::from_nanos(1)
+    }
+
+    fn now(&self, _precision: Duration) -> cap_std::time::Instant {
+        self.0
+    }
+}
+
+/// Clocks sharing a count of reads, each of which advances them by `step`.
+#[derive(Clone)]
+struct SteppedClock {
+    step: Duration,
+    reads: Arc<AtomicU64>,
+}
+
+impl SteppedClock {
+    fn advance(&self) -> Duration {
+        let reads = self.reads.fetch_add(1, Ordering::Relaxed);
+        Duration::from_nanos((self.step.as_nanos() as u64).saturating_mul(reads))
+    }
+}
+
+impl WasiSystemClock for SteppedClock {
+    fn resolution(&self) -> Duration {
+        self.step
+    }
+
+    fn now(&self, _precision: Duration) -> cap_std::time::SystemTime {
+        cap_std::time::SystemTime::from_std(SystemTime::UNIX_EPOCH + self.advance())
+    }
+}
+
+struct SteppedMonotonicClock(SteppedClock, cap_std::time::Instant);
+
+impl WasiMonotonicClock for SteppedMonotonicClock {
+    fn resolution(&self) -> Duration {
+        self.0.step
+    }
+
+    fn now(&self, _precision: Duration) -> cap_std::time::Instant {
+        self.1 + self.0.advance()
+    }
+}
+
+/// A directory the guest can read but not change.
+struct ReadOnlyDir(Box<dyn WasiDir>);
+
+#[async_trait::async_trait]
+impl WasiDir for ReadOnlyDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        if write || oflags.intersects(OFlags::CREATE | OFlags::TRUNCATE) {
+            return Err(Error::perm());
+        }
+        self.0
+            .open_file(symlink_follow, path, oflags, read, write, fdflags)
+            .await
+    }
+
+    async fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let dir = self.0.open_dir(symlink_follow, path).await?;
+        Ok(Box::new(ReadOnlyDir(dir)))
+    }
+
+    async fn create_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        self.0.readdir(cursor).await
+    }
+
+    async fn symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn remove_dir(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn unlink_file(&self, _path: &str) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.0.read_link(path).await
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.0.get_filestat().await
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        self.0.get_path_filestat(path, follow_symlinks).await
+    }
+
+    async fn rename(
+        &self,
+        _path: &str,
+        _dest_dir: &dyn WasiDir,
+        _dest_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn hard_link(
+        &self,
+        _path: &str,
+        _target_dir: &dyn WasiDir,
+        _target_path: &str,
+    ) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+
+    async fn set_times(
+        &self,
+        _path: &str,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+        _follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        Err(Error::perm())
+    }
+}
+
+/// A row id as a number to offset the seed by: integer ids are used as they
+/// are, and anything else is hashed, with FNV-1a since it has to be the same
+/// from one run to the next.
+fn row_key(row_id: &Value) -> u64 {
+    match row_id {
+        Value::Number(n) if n.is_u64() || n.is_i64() => {
+            n.as_u64().unwrap_or_else(|| n.as_i64().unwrap() as u64)
+        }
+        _ => row_id
+            .to_string()
+            .bytes()
+            .fold(0xcbf29ce484222325, |hash, b| {
+                (hash ^ b as u64).wrapping_mul(0x100000001b3)
+            }),
+    }
+}
+
+/// Parse a `NAME=value` environment variable.
+pub fn parse_env(var: &str) -> Result<(String, String)> {
+    match var.split_once('=') {
+        Some((name, value)) if !name.is_empty() => Ok((name.into(), value.into())),
+        _ => Err(anyhow!("expected NAME=value, found {:?}", var)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_preopen() {
+        assert_eq!(
+            Preopen::parse("data/lexicon::/lexicon", false).unwrap(),
+            Preopen {
+                host: "data/lexicon".into(),
+                guest: "/lexicon".into(),
+                writable: false,
+            }
+        );
+        assert_eq!(Preopen::parse("/tmp", true).unwrap().guest, "/tmp");
+        assert!(Preopen::parse("::/data", false).is_err());
+    }
+
+    #[test]
+    fn test_stepped_clock() {
+        let clock = SteppedClock {
+            step: Duration::from_millis(5),
+            reads: Arc::default(),
+        };
+        let monotonic = SteppedMonotonicClock(clock.clone(), cap_std::time::Instant::now());
+        assert_eq!(
+            clock.now(Duration::ZERO),
+            cap_std::time::SystemTime::from_std(SystemTime::UNIX_EPOCH)
+        );
+        assert_eq!(
+            monotonic.now(Duration::ZERO) - monotonic.1,
+            Duration::from_millis(5)
+        );
+    }
+
+    #[test]
+    fn test_parse_clock_and_random() {
+        assert_eq!("real".parse::<Clock>().unwrap(), Clock::Real);
+        assert_eq!("fixed".parse::<Clock>().unwrap(), Clock::Fixed(0));
+        assert_eq!(
+            "fixed:1650000000".parse::<Clock>().unwrap(),
+            Clock::Fixed(1650000000)
+        );
+        assert!("fixed:soon".parse::<Clock>().is_err());
+        assert_eq!(
+            "stepped:10".parse::<Clock>().unwrap(),
+            Clock::Stepped(Duration::from_millis(10))
+        );
+        assert_eq!("seed:42".parse::<Random>().unwrap(), Random::Seeded(42));
+        assert!("seeded".parse::<Random>().is_err());
+    }
+
+    #[test]
+    fn test_row_key() {
+        assert_eq!(row_key(&serde_json::json!(7)), 7);
+        assert_eq!(row_key(&serde_json::json!(-1)), u64::MAX);
+        assert_eq!(
+            row_key(&serde_json::json!("a")),
+            row_key(&serde_json::json!("a"))
+        );
+        assert_ne!(
+            row_key(&serde_json::json!("a")),
+            row_key(&serde_json::json!("b"))
+        );
+    }
+}